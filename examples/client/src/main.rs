@@ -1,19 +1,17 @@
 use mril_transfer_protocol::{
     package::{
-        packages::{PackageReportSpeed, Packages, PackagesBatchSize},
+        options::WriteOptions,
+        packages::{Packages, PackagesBatchSize},
+        report::PackageReportSpeed,
         PackageSize,
     },
-    shake::Handshake,
     stream::Stream,
 };
-use std::{fs, net::TcpStream};
+use std::fs::File;
 
 // fn main() {
 //     let tcp_stream = TcpStream::connect("127.0.0.1:3400").expect("expected connection");
-//     let mut stream = Stream {
-//         handshaken: Handshake::UNSHAKEN,
-//         tcp_stream,
-//     };
+//     let mut stream = Stream::unshaken(tcp_stream);
 
 //     let mut rand = rand::thread_rng();
 //     let bytes = vec![0; 100_000_000]
@@ -46,18 +44,15 @@ fn main() {
             break;
         }
     
-        let tcp_stream = TcpStream::connect("127.0.0.1:3400").expect("expected connection");
-        let mut stream = Stream {
-            handshaken: Handshake::UNSHAKEN,
-            tcp_stream,
-        };
+        let mut stream = Stream::connect("127.0.0.1:3400").expect("expected connection");
 
-        let bytes = fs::read("./music.flac").unwrap();
-        let mut packages = Packages::new(bytes);
+        let file = File::open("./music.flac").unwrap();
+        let length = file.metadata().unwrap().len() as usize;
+        let mut packages = Packages::new(vec![]);
     
-        packages.set_package_size(PackageSize::LARGE);
+        packages.set_package_size(PackageSize::ADAPTIVE);
         packages.set_report_speed(PackageReportSpeed::FASTEST);
-        packages.set_batch_size(PackagesBatchSize::MAX);
+        packages.set_batch_size(PackagesBatchSize::ADAPTIVE);
 
         println!("A: {}", a);
     
@@ -65,6 +60,8 @@ fn main() {
         //     // println!("\n\n[client]: {:?}", report);
         // });
     
-        packages.write_to(&mut stream.tcp_stream);
+        stream
+            .send_from_reader(packages, file, Some(length), WriteOptions::new())
+            .expect("Expected to write packages");
     }
 }
@@ -1,30 +1,26 @@
-use std::{net::TcpListener, fs};
+use std::fs::File;
 
-use mril_transfer_protocol::{package::packages::Packages, shake::Handshake, stream::Stream};
+use mril_transfer_protocol::{listener::MtpListener, package::options::ReadOptions};
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:3400").expect("Tcp listener");
+    let listener = MtpListener::bind("127.0.0.1:3400").expect("Mtp listener");
     println!("Listening on port 3400");
     let mut t = 0;
 
-    loop {
+    for stream in listener.incoming() {
         t += 1;
-        let (tcp_stream, _) = listener.accept().expect("Tcp stream");
-        // mril_transfer_protocol::stream::Stream::connect_stream(tcp_stream);
-        let mut stream = Stream {
-            handshaken: Handshake::UNSHAKEN,
-            tcp_stream,
-        };
+        let mut stream = stream.expect("Mtp stream");
 
         let now = std::time::Instant::now();
 
-        let packages = Packages::read_from(&mut stream.tcp_stream);
+        let file = File::create("music.flac").unwrap();
+        let received = stream
+            .receive_into(file, ReadOptions::new())
+            .expect("Expected packages");
 
-        assert_eq!(packages.data.len(), 112591267);
+        assert_eq!(received, 112591267);
 
-        // println!("[server]: received {:?} bytes", packages.data.len());
-
-        fs::write("music.flac", packages.data).unwrap();
+        // println!("[server]: received {:?} bytes", received);
         
         println!("time taken {:?} : {}", now.elapsed(), t);
     }
@@ -0,0 +1,705 @@
+//! Async variants of [`crate::stream::Stream`], [`Package`] and [`Packages`]
+//! built on tokio, enabled with the `tokio` feature.
+//!
+//! The wire format is the same as the blocking API so sync and
+//! async peers can talk to each other.
+
+use std::io::Cursor;
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+use crate::{
+    bufferable::Bufferable,
+    capabilities::Capabilities,
+    codec,
+    crypto::SessionKey,
+    error::{MtpError, Result},
+    package::{
+        check_length, checksum_length,
+        control::{check_response, response},
+        max_sealed_length,
+        meta::{PackageMeta, PackageType},
+        options::{ReadOptions, WriteOptions},
+        packages::{Chunks, Packages, PackagesReceiver, PackagesSender, Received},
+        Package,
+    },
+    shake::{
+        Challenge, ChallengeResponse, Handshake, HandshakeBuilder, HandshakeState, KeyShare, Shake,
+    },
+    stream::{check_package_size, check_packages},
+    utils::MAX_EXTENDED_LENGTH,
+};
+
+/// Field of a frame as found on the wire
+pub(crate) enum FrameField {
+    /// Exactly that many bytes
+    Fixed(usize),
+    /// A 3 bytes length header followed by that many bytes
+    LengthPrefixed,
+    /// A varint, see [`codec::encode_varint`]
+    Varint,
+    /// Data of a package, its length header depends on the meta header starting the frame
+    PackageData,
+    /// Checksum of a package, its length depends on the meta header starting the frame
+    Checksum,
+}
+
+/// Layout of a [`Bufferable`] frame, lets async streams read the exact bytes
+/// of a frame before parsing them with [`Bufferable::from_stream`]
+pub(crate) trait Framed: Bufferable {
+    const FRAME: &'static [FrameField];
+}
+
+impl Framed for Capabilities {
+    const FRAME: &'static [FrameField] = &[FrameField::Fixed(9), FrameField::Varint];
+}
+
+impl Framed for Shake {
+    const FRAME: &'static [FrameField] = &[
+        FrameField::Fixed(9),
+        FrameField::Varint,
+        FrameField::LengthPrefixed,
+        FrameField::LengthPrefixed,
+    ];
+}
+
+impl Framed for KeyShare {
+    const FRAME: &'static [FrameField] = &[FrameField::LengthPrefixed];
+}
+
+impl Framed for Challenge {
+    const FRAME: &'static [FrameField] = &[FrameField::Fixed(32)];
+}
+
+impl Framed for ChallengeResponse {
+    const FRAME: &'static [FrameField] = &[FrameField::LengthPrefixed];
+}
+
+impl Framed for Package {
+    const FRAME: &'static [FrameField] = &[
+        FrameField::Fixed(16),
+        FrameField::PackageData,
+        FrameField::Checksum,
+    ];
+}
+
+/// Same as [`crate::utils::read_bytes`] over an async stream, appending to the buffer
+async fn read_bytes<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    length: usize,
+    buffer: &mut Vec<u8>,
+) -> Result<()> {
+    let read = stream.take(length as u64).read_to_end(buffer).await?;
+
+    if read != length {
+        return Err(MtpError::PeerAborted);
+    }
+
+    Ok(())
+}
+
+/// Reads a whole frame and parses it
+pub(crate) async fn read_framed<T: Framed, R: AsyncRead + Unpin>(stream: &mut R) -> Result<T> {
+    read_framed_limited(stream, MAX_EXTENDED_LENGTH).await
+}
+
+/// Same as [`read_framed`] but package data longer than `max_length`
+/// is refused before being read
+pub(crate) async fn read_framed_limited<T: Framed, R: AsyncRead + Unpin>(
+    stream: &mut R,
+    max_length: usize,
+) -> Result<T> {
+    let mut buffer = vec![];
+
+    for field in T::FRAME {
+        match field {
+            FrameField::Fixed(length) => read_bytes(stream, *length, &mut buffer).await?,
+            FrameField::LengthPrefixed => {
+                let mut length_bytes = [0; 3];
+                stream.read_exact(&mut length_bytes).await?;
+                buffer.extend_from_slice(&length_bytes);
+
+                read_bytes(stream, codec::decode(length_bytes)?, &mut buffer).await?;
+            }
+            FrameField::Varint => {
+                read_varint(stream, &mut buffer).await?;
+            }
+            FrameField::PackageData => {
+                let length = if frame_meta(&buffer)?.has_extended_length() {
+                    read_varint(stream, &mut buffer).await?
+                } else {
+                    let mut length_bytes = [0; 3];
+                    stream.read_exact(&mut length_bytes).await?;
+                    buffer.extend_from_slice(&length_bytes);
+
+                    codec::decode(length_bytes)?
+                };
+
+                read_bytes(stream, check_length(length, max_length)?, &mut buffer).await?;
+            }
+            FrameField::Checksum => {
+                let length = checksum_length(frame_meta(&buffer)?.checksum)?;
+
+                read_bytes(stream, length, &mut buffer).await?;
+            }
+        }
+    }
+
+    T::from_stream(&mut Cursor::new(buffer))
+}
+
+/// Reads the bytes of a varint into the buffer and decodes them
+async fn read_varint<R: AsyncRead + Unpin>(stream: &mut R, buffer: &mut Vec<u8>) -> Result<usize> {
+    let start = buffer.len();
+
+    loop {
+        let byte = read_byte(stream).await?;
+        buffer.push(byte);
+
+        if byte & 0x80 == 0 || buffer.len() - start == codec::MAX_VARINT_LENGTH {
+            return Ok(codec::decode_varint(&buffer[start..])?.0);
+        }
+    }
+}
+
+/// Meta header starting a package frame
+fn frame_meta(buffer: &[u8]) -> Result<PackageMeta> {
+    let meta = buffer[..16]
+        .try_into()
+        .map_err(|_| MtpError::MalformedHeader("package frame without meta header"))?;
+
+    PackageMeta::from_bytes(meta)
+}
+
+impl<R: AsyncRead + Unpin> Chunks<R> {
+    /// Same as [`Chunks::next`] over an async reader
+    async fn next_async(&mut self, size: usize) -> Result<(Vec<u8>, bool)> {
+        let (data, read_size) = match self.take_ahead() {
+            Some(ahead) => ahead,
+            None => (read_chunk(self.reader(), size).await?, size),
+        };
+
+        let next = if data.len() < read_size {
+            vec![]
+        } else {
+            read_chunk(self.reader(), size).await?
+        };
+
+        Ok(self.advance(data, next, size))
+    }
+}
+
+/// Same as `read_chunk` of [`crate::package::packages`] over an async reader
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, size: usize) -> Result<Vec<u8>> {
+    let mut chunk = vec![];
+    reader.take(size as u64).read_to_end(&mut chunk).await?;
+
+    Ok(chunk)
+}
+
+async fn read_byte<R: AsyncRead + Unpin>(stream: &mut R) -> Result<u8> {
+    Ok(stream.read_u8().await?)
+}
+
+/// Reads the receiver's response, waiting for as long as the receiver keeps the transfer paused
+async fn read_response<R: AsyncRead + Unpin>(stream: &mut R) -> Result<()> {
+    while check_response(read_byte(stream).await?)? {}
+
+    Ok(())
+}
+
+impl Package {
+    /// Same as [`Bufferable::from_stream`] over an async stream
+    pub async fn from_async_stream<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Self> {
+        read_framed(stream).await
+    }
+
+    /// Writes the package to an async stream
+    pub async fn write_async<W: AsyncWrite + Unpin>(self, stream: &mut W) -> Result<()> {
+        stream.write_all(&self.to_buffer()?).await?;
+
+        Ok(())
+    }
+}
+
+impl Packages {
+    /// Same as [`Packages::write_to`] over an async stream
+    pub async fn write_to_async<S: AsyncRead + AsyncWrite + Unpin>(
+        mut self,
+        stream: &mut S,
+    ) -> Result<()> {
+        let data = std::mem::take(&mut self.data);
+
+        self.write_from_reader_async(
+            stream,
+            data.as_slice(),
+            Some(data.len()),
+            WriteOptions::new(),
+        )
+        .await
+    }
+
+    /// Same as [`Packages::write_from_reader`] over async streams, resumable
+    /// transfers are refused with [`MtpError::Incompatible`]
+    pub async fn write_from_reader_async<
+        S: AsyncRead + AsyncWrite + Unpin,
+        R: AsyncRead + Unpin,
+    >(
+        mut self,
+        stream: &mut S,
+        reader: R,
+        length_hint: Option<usize>,
+        options: WriteOptions,
+    ) -> Result<()> {
+        if options.transfer_id.is_some() {
+            return Err(MtpError::Incompatible(
+                "resumable transfers are not supported by async streams",
+            ));
+        }
+
+        let mut writer = PackagesSender::new(
+            &mut self,
+            options.session_key.as_ref(),
+            options.max_package_size,
+            length_hint,
+        )?;
+
+        stream.write_all(&writer.header()).await?;
+
+        let mut chunks = Chunks::new(reader);
+
+        loop {
+            let (data, last) = chunks.next_async(writer.package_size()).await?;
+
+            let (buffer, expects_response) = writer.frame(data, last)?;
+            stream.write_all(&buffer).await?;
+
+            if expects_response {
+                read_response(stream).await?;
+                writer.acknowledged();
+            }
+
+            writer.report();
+
+            if last {
+                break;
+            }
+        }
+
+        read_response(stream).await?;
+
+        writer.finish();
+
+        Ok(())
+    }
+
+    /// Same as [`Packages::read_from`] over an async stream
+    pub async fn read_from_async<S: AsyncRead + AsyncWrite + Unpin>(
+        stream: &mut S,
+    ) -> Result<Self> {
+        let mut packages = Self::new(vec![]);
+
+        Self::read_into_async(stream, &mut packages.data, ReadOptions::new()).await?;
+
+        Ok(packages)
+    }
+
+    /// Same as [`Packages::read_into`] over async streams, controlled and
+    /// resumable transfers are refused with [`MtpError::Incompatible`]
+    pub async fn read_into_async<S: AsyncRead + AsyncWrite + Unpin, W: AsyncWrite + Unpin>(
+        stream: &mut S,
+        mut sink: W,
+        options: ReadOptions<'_>,
+    ) -> Result<usize> {
+        if options.control.is_some() || options.log.is_some() {
+            return Err(MtpError::Incompatible(
+                "controlled and resumable transfers are not supported by async streams",
+            ));
+        }
+
+        let mut written = 0;
+        let header = [read_byte(stream).await?];
+        let mut receiver =
+            PackagesReceiver::new(header, options.session_key, options.max_package_size)?;
+
+        if let Some(reporter) = options.reporter {
+            receiver.set_reporter(reporter);
+        }
+
+        loop {
+            let received = receive_package_async(stream, &mut receiver).await?;
+
+            sink.write_all(&received.data).await.map_err(MtpError::Io)?;
+            written += received.data.len();
+
+            if !received.more {
+                sink.flush().await.map_err(MtpError::Io)?;
+
+                return Ok(written);
+            }
+        }
+    }
+}
+
+/// Receives a single package and answers it, the final response is written after the last one
+async fn receive_package_async<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    receiver: &mut PackagesReceiver,
+) -> Result<Received> {
+    let package: Package = read_framed_limited(stream, receiver.max_data_length()).await?;
+    let footer = read_byte(stream).await?;
+
+    let received = receiver.receive(package, footer)?;
+
+    if received.acknowledge {
+        stream.write_all(&[response::CONTINUE]).await?;
+    }
+
+    if !received.more {
+        let mut transfer_digest = vec![];
+        read_bytes(stream, receiver.digest_length(), &mut transfer_digest).await?;
+        receiver.verify_digest(&transfer_digest)?;
+
+        stream.write_all(&[response::CONTINUE]).await?;
+    }
+
+    Ok(received)
+}
+
+impl HandshakeBuilder {
+    /// Same as [`HandshakeBuilder::perform`] over an async stream
+    pub async fn perform_async<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+    ) -> Result<Handshake> {
+        Ok(self.negotiate_async(stream).await?.0)
+    }
+
+    async fn negotiate_async<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+    ) -> Result<(Handshake, Capabilities)> {
+        let (mut state, shake) = HandshakeState::start(self)?;
+
+        stream.write_all(&shake.to_buffer()?).await?;
+        let key_share = state.receive_shake(read_framed(stream).await?)?;
+
+        stream.write_all(&key_share.to_buffer()?).await?;
+        let challenge = state.receive_key_share(read_framed(stream).await?)?;
+
+        stream.write_all(&challenge.to_buffer()?).await?;
+        let response = state.receive_challenge(read_framed(stream).await?)?;
+
+        stream.write_all(&response.to_buffer()?).await?;
+        state.finish(read_framed(stream).await?)
+    }
+
+    /// Same as [`HandshakeBuilder::connect_stream`] for a tokio tcp stream
+    pub async fn connect_stream_async(&self, mut tcp_stream: TcpStream) -> Result<AsyncStream> {
+        let (handshaken, capabilities) = self.negotiate_async(&mut tcp_stream).await?;
+
+        Ok(AsyncStream {
+            tcp_stream,
+            handshaken,
+            capabilities,
+        })
+    }
+
+    /// Same as [`HandshakeBuilder::connect`] for a tokio tcp stream
+    pub async fn connect_async<T: ToSocketAddrs>(&self, addr: T) -> Result<AsyncStream> {
+        let tcp_stream = TcpStream::connect(addr).await.map_err(MtpError::Io)?;
+
+        self.connect_stream_async(tcp_stream).await
+    }
+}
+
+/// Async counterpart of [`crate::stream::Stream`]
+#[derive(Debug)]
+pub struct AsyncStream {
+    pub tcp_stream: TcpStream,
+    pub handshaken: Handshake,
+    /// Capabilities shared by both parties, [`Capabilities::BASELINE`] when `UNSHAKEN`
+    pub capabilities: Capabilities,
+}
+
+impl AsyncStream {
+    /// Starts configuring the handshake (identity, trust store, payload...)
+    /// performed when connecting
+    pub fn handshake() -> HandshakeBuilder {
+        HandshakeBuilder::new()
+    }
+
+    pub async fn connect_stream(tcp_stream: TcpStream) -> Result<Self> {
+        Self::handshake().connect_stream_async(tcp_stream).await
+    }
+
+    pub async fn connect<T: ToSocketAddrs>(addr: T) -> Result<Self> {
+        Self::handshake().connect_async(addr).await
+    }
+
+    /// Wraps a tcp stream without performing a handshake, nothing
+    /// sent or received on it will be encrypted
+    pub fn unshaken(tcp_stream: TcpStream) -> Self {
+        Self {
+            tcp_stream,
+            handshaken: Handshake::UNSHAKEN,
+            capabilities: Capabilities::BASELINE,
+        }
+    }
+
+    /// Payload the other party attached to its shake, `None` when `UNSHAKEN`
+    pub fn peer_payload(&self) -> Option<&[u8]> {
+        self.handshaken.peer_payload()
+    }
+
+    fn session_key(&self) -> Result<&SessionKey> {
+        match &self.handshaken {
+            Handshake::SHAKEN(_, _, session_key) => Ok(session_key),
+            Handshake::UNSHAKEN => Err(MtpError::Unshaken),
+        }
+    }
+
+    /// Same as [`crate::stream::Stream::send_package`]
+    pub async fn send_package(&mut self, package: Package) -> Result<()> {
+        check_package_size(&self.capabilities, &package)?;
+
+        let encrypted_package = package.seal(self.session_key()?)?;
+
+        encrypted_package.write_async(&mut self.tcp_stream).await
+    }
+
+    /// Same as [`crate::stream::Stream::receive_package`]
+    pub async fn receive_package(&mut self) -> Result<Package> {
+        let session_key = self.session_key()?.clone();
+
+        let max_length = max_sealed_length(self.capabilities.max_package_size);
+
+        let package = read_framed_limited::<Package, _>(&mut self.tcp_stream, max_length).await?;
+        package.meta.check_type(PackageType::PACKAGE)?;
+
+        package.open(&session_key)
+    }
+
+    /// Same as [`crate::stream::Stream::send_packages`]
+    pub async fn send_packages(&mut self, mut packages: Packages) -> Result<()> {
+        let data = std::mem::take(&mut packages.data);
+
+        self.send_from_reader(
+            packages,
+            data.as_slice(),
+            Some(data.len()),
+            WriteOptions::new(),
+        )
+        .await
+    }
+
+    /// Same as [`crate::stream::Stream::send_from_reader`]
+    pub async fn send_from_reader<R: AsyncRead + Unpin>(
+        &mut self,
+        packages: Packages,
+        reader: R,
+        length_hint: Option<usize>,
+        options: WriteOptions,
+    ) -> Result<()> {
+        let options = options
+            .session_key(self.session_key()?)
+            .max_package_size(self.capabilities.max_package_size);
+
+        check_packages(&self.capabilities, &packages)?;
+
+        packages
+            .write_from_reader_async(&mut self.tcp_stream, reader, length_hint, options)
+            .await
+    }
+
+    /// Same as [`crate::stream::Stream::receive_packages`]
+    pub async fn receive_packages(&mut self) -> Result<Packages> {
+        let mut packages = Packages::new(vec![]);
+
+        self.receive_into(&mut packages.data, ReadOptions::new())
+            .await?;
+
+        Ok(packages)
+    }
+
+    /// Same as [`crate::stream::Stream::receive_into`]
+    pub async fn receive_into<W: AsyncWrite + Unpin>(
+        &mut self,
+        sink: W,
+        options: ReadOptions<'_>,
+    ) -> Result<usize> {
+        let options = options
+            .session_key(self.session_key()?)
+            .max_package_size(self.capabilities.max_package_size);
+
+        Packages::read_into_async(&mut self.tcp_stream, sink, options).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use crate::{
+        asynchronous::AsyncStream,
+        capabilities::Capabilities,
+        error::MtpError,
+        package::{
+            meta::{PackageMeta, PackageType},
+            packages::{Packages, PackagesBatchSize},
+            Package, PackageSize,
+        },
+        shake::HandshakeBuilder,
+        stream::Stream,
+    };
+
+    /// Hands a stream handshaken by the blocking side over to tokio
+    fn into_async(stream: Stream) -> AsyncStream {
+        stream.tcp_stream.set_nonblocking(true).unwrap();
+
+        AsyncStream {
+            tcp_stream: tokio::net::TcpStream::from_std(stream.tcp_stream).unwrap(),
+            handshaken: stream.handshaken,
+            capabilities: stream.capabilities,
+        }
+    }
+
+    #[tokio::test]
+    async fn package_over_async_stream() {
+        let (mut sender, mut receiver) = tokio::io::duplex(64);
+
+        let meta = PackageMeta::new(1, PackageType::PACKAGE);
+        let package = Package::new((0..5000).map(|i| (i % 256) as u8).collect(), meta);
+
+        let expected = package.clone();
+        let writer = tokio::spawn(async move { package.write_async(&mut sender).await.unwrap() });
+
+        assert_eq!(
+            Package::from_async_stream(&mut receiver).await.unwrap(),
+            expected
+        );
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn extended_package_over_async_stream() {
+        let (mut sender, mut receiver) = tokio::io::duplex(64 * 1024);
+
+        let meta = PackageMeta::new(1, PackageType::PACKAGE);
+        let package = Package::new(vec![3; usize::pow(2, 24) + 1], meta);
+
+        let expected = package.clone();
+        let writer = tokio::spawn(async move { package.write_async(&mut sender).await.unwrap() });
+
+        assert_eq!(
+            Package::from_async_stream(&mut receiver).await.unwrap(),
+            expected
+        );
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn packages_over_async_stream() {
+        let (mut sender, mut receiver) = tokio::io::duplex(1024);
+
+        let data = (0..50_000).map(|i| (i % 256) as u8).collect::<Vec<u8>>();
+        let mut packages = Packages::new(data.clone());
+        packages.set_package_size(PackageSize::SMALL);
+        packages.set_batch_size(PackagesBatchSize::SMALL);
+
+        let receiver =
+            tokio::spawn(async move { Packages::read_from_async(&mut receiver).await.unwrap() });
+        packages.write_to_async(&mut sender).await.unwrap();
+
+        assert_eq!(receiver.await.unwrap().data, data);
+    }
+
+    #[tokio::test]
+    async fn async_streams_exchange_encrypted_packages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (tcp_stream, _) = listener.accept().await.unwrap();
+            let mut stream = AsyncStream::connect_stream(tcp_stream).await.unwrap();
+
+            stream.receive_packages().await.unwrap()
+        });
+
+        let mut client = AsyncStream::connect(addr).await.unwrap();
+
+        let data = (0..100_000).map(|i| (i % 256) as u8).collect::<Vec<u8>>();
+        let mut packages = Packages::new(data.clone());
+        packages.set_package_size(PackageSize::MEDIUM);
+        client.send_packages(packages).await.unwrap();
+
+        assert_eq!(server.await.unwrap().data, data);
+    }
+
+    #[tokio::test]
+    async fn async_stream_refuses_package_above_negotiated_size() {
+        let (server, client) = crate::tests::handshaken_pair(Capabilities {
+            max_package_size: 4095,
+            ..Capabilities::default()
+        });
+        let (mut server, mut client) = (into_async(server), into_async(client));
+
+        // Written around the sending checks, as a peer ignoring the negotiated size would
+        let meta = PackageMeta::new(1, PackageType::PACKAGE);
+        let package = Package::new(vec![5; 10_000], meta)
+            .seal(client.session_key().unwrap())
+            .unwrap();
+        package.write_async(&mut client.tcp_stream).await.unwrap();
+
+        assert!(matches!(
+            server.receive_package().await,
+            Err(MtpError::OversizedLength { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn sync_and_async_peers_interoperate() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let data = (0..20_000).map(|i| (i % 256) as u8).collect::<Vec<u8>>();
+        let sent = data.clone();
+
+        let client = std::thread::spawn(move || {
+            let mut stream = Stream::handshake()
+                .payload(b"sync client".to_vec())
+                .connect(addr)
+                .unwrap();
+
+            let meta = PackageMeta::new(1, PackageType::PACKAGE);
+            stream
+                .send_package(Package::new(b"hello".to_vec(), meta))
+                .unwrap();
+            stream.send_packages(Packages::new(sent)).unwrap();
+
+            stream.receive_package().unwrap()
+        });
+
+        let (tcp_stream, _) = listener.accept().await.unwrap();
+        let mut server = HandshakeBuilder::new()
+            .connect_stream_async(tcp_stream)
+            .await
+            .unwrap();
+
+        assert_eq!(server.peer_payload(), Some(&b"sync client"[..]));
+        assert_eq!(server.receive_package().await.unwrap().data, b"hello");
+        assert_eq!(server.receive_packages().await.unwrap().data, data);
+
+        let meta = PackageMeta::new(2, PackageType::PACKAGE);
+        let reply = Package::new(b"hello back".to_vec(), meta);
+        server.send_package(reply.clone()).await.unwrap();
+
+        let received = tokio::task::spawn_blocking(move || client.join().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(received, reply);
+    }
+}
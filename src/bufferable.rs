@@ -1,6 +1,8 @@
-use std::net::TcpStream;
+use std::io::Read;
 
-pub trait Bufferable {
-    fn to_buffer(self) -> Vec<u8>;
-    fn from_stream(tcp_stream: &mut TcpStream) -> Self;
+use crate::error::Result;
+
+pub trait Bufferable: Sized {
+    fn to_buffer(self) -> Result<Vec<u8>>;
+    fn from_stream<R: Read>(stream: &mut R) -> Result<Self>;
 }
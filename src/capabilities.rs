@@ -0,0 +1,209 @@
+use std::io::Read;
+
+use crate::{
+    bufferable::Bufferable,
+    codec,
+    error::{MtpError, Result},
+    utils::{read_array, MAX_EXTENDED_LENGTH, MAX_HEADER_LENGTH},
+};
+
+/// Bytes every shake starts with, anything else is not an MTP peer
+pub const MAGIC: [u8; 4] = *b"MTP\x00";
+
+/// Version of the wire format spoken by this crate
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Oldest version of the wire format this crate can still speak
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// Bits of the capability bitset exchanged in the shake
+/// - bits 0 - 7; encryption ciphers
+/// - bits 8 - 15; compression codecs
+/// - bits 16 - 31; transfer features
+pub mod capability {
+    pub const AES_256_GCM: u32 = 1 << 0;
+
+    pub const ZSTD: u32 = 1 << 8;
+    pub const LZ4: u32 = 1 << 9;
+
+    pub const RESUMABLE: u32 = 1 << 16;
+    pub const MULTIPLEXED: u32 = 1 << 17;
+
+    /// Bits used to pick the cipher of the session
+    pub const CIPHERS: u32 = 0xff;
+}
+
+/// What a party is able to speak, sent by both parties in their shake.
+///
+/// The capabilities of a [`crate::stream::Stream`] are the intersection
+/// of both parties' capabilities, see [`Capabilities::intersect`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version: u8,
+    /// Bitset of [`capability`] flags
+    pub flags: u32,
+    /// Largest package data the party accepts, packages above
+    /// [`MAX_HEADER_LENGTH`] are sent with an extended length header
+    pub max_package_size: usize,
+}
+
+impl Default for Capabilities {
+    /// Everything this crate supports
+    fn default() -> Self {
+        let mut flags = capability::AES_256_GCM | capability::RESUMABLE | capability::MULTIPLEXED;
+
+        if cfg!(feature = "zstd") {
+            flags |= capability::ZSTD;
+        }
+        if cfg!(feature = "lz4") {
+            flags |= capability::LZ4;
+        }
+
+        Self {
+            version: PROTOCOL_VERSION,
+            flags,
+            max_package_size: MAX_HEADER_LENGTH,
+        }
+    }
+}
+
+impl Capabilities {
+    /// What any peer can speak without negotiating, used by unshaken streams
+    pub const BASELINE: Self = Self {
+        version: MIN_PROTOCOL_VERSION,
+        flags: 0,
+        max_package_size: MAX_HEADER_LENGTH,
+    };
+
+    pub fn supports(&self, flag: u32) -> bool {
+        self.flags & flag == flag
+    }
+
+    /// Capabilities both parties share, fails when they have no
+    /// version or no cipher in common
+    pub fn intersect(&self, other: &Self) -> Result<Self> {
+        let version = self.version.min(other.version);
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(MtpError::UnsupportedVersion(version));
+        }
+
+        let flags = self.flags & other.flags;
+        if flags & capability::CIPHERS == 0 {
+            return Err(MtpError::Incompatible("no encryption cipher in common"));
+        }
+
+        Ok(Self {
+            version,
+            flags,
+            max_package_size: self.max_package_size.min(other.max_package_size),
+        })
+    }
+}
+
+impl Bufferable for Capabilities {
+    /// Buffer model
+    /// - First 4 bytes (0, 3) [`MAGIC`]
+    /// - Next byte (4) protocol version
+    /// - Next 4 bytes (5, 8) capability flags
+    /// - Rest (9, ..) max package size as a varint
+    fn to_buffer(self) -> Result<Vec<u8>> {
+        let mut buffer = MAGIC.to_vec();
+
+        buffer.push(self.version);
+        buffer.extend_from_slice(&self.flags.to_be_bytes());
+        buffer.append(&mut codec::encode_varint(
+            self.max_package_size.min(MAX_EXTENDED_LENGTH),
+        ));
+
+        Ok(buffer)
+    }
+
+    fn from_stream<R: Read>(stream: &mut R) -> Result<Self> {
+        if read_array::<R, 4>(stream)? != MAGIC {
+            return Err(MtpError::MalformedHeader(
+                "shake does not start with MTP magic",
+            ));
+        }
+
+        let [version] = read_array::<R, 1>(stream)?;
+        let flags = u32::from_be_bytes(read_array::<R, 4>(stream)?);
+        let max_package_size = codec::read_varint(stream)?;
+
+        Ok(Self {
+            version,
+            flags,
+            max_package_size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        bufferable::Bufferable,
+        capabilities::{capability, Capabilities},
+        error::MtpError,
+    };
+
+    #[test]
+    fn capabilities_round_trip() {
+        let capabilities = Capabilities {
+            version: 3,
+            flags: capability::AES_256_GCM | capability::LZ4 | capability::RESUMABLE,
+            max_package_size: 65535,
+        };
+
+        let mut buffer = Cursor::new(capabilities.to_buffer().unwrap());
+
+        assert_eq!(
+            Capabilities::from_stream(&mut buffer).unwrap(),
+            capabilities
+        );
+    }
+
+    #[test]
+    fn intersection() {
+        let ours = Capabilities {
+            version: 2,
+            flags: capability::AES_256_GCM | capability::ZSTD | capability::RESUMABLE,
+            max_package_size: 65535,
+        };
+        let theirs = Capabilities {
+            version: 1,
+            flags: capability::AES_256_GCM | capability::ZSTD | capability::LZ4,
+            max_package_size: 4095,
+        };
+
+        let negotiated = ours.intersect(&theirs).unwrap();
+
+        assert_eq!(negotiated.version, 1);
+        assert!(negotiated.supports(capability::AES_256_GCM | capability::ZSTD));
+        assert!(!negotiated.supports(capability::RESUMABLE));
+        assert_eq!(negotiated.max_package_size, 4095);
+    }
+
+    #[test]
+    fn no_common_cipher() {
+        let theirs = Capabilities {
+            flags: capability::RESUMABLE | capability::MULTIPLEXED,
+            ..Capabilities::default()
+        };
+
+        assert!(matches!(
+            Capabilities::default().intersect(&theirs),
+            Err(MtpError::Incompatible(_))
+        ));
+    }
+
+    #[test]
+    fn wrong_magic() {
+        let mut buffer = Cursor::new(b"HTTP/1.1 200 OK".to_vec());
+
+        assert!(matches!(
+            Capabilities::from_stream(&mut buffer),
+            Err(MtpError::MalformedHeader(_))
+        ));
+    }
+}
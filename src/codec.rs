@@ -0,0 +1,207 @@
+//! Integers as written on the wire, either fixed width big endian
+//! (`u16`, `u24`, `u32` and `u64` being widths of 2, 3, 4 and 8 bytes)
+//! or LEB128 varints.
+//! ```ignore
+//! let length = codec::encode::<3>(data.len())?;
+//! assert_eq!(codec::decode(length)?, data.len());
+//! ```
+
+use std::io::Read;
+
+use crate::{
+    error::{MtpError, Result},
+    utils::read_array,
+};
+
+/// Longest varint needed for a 64 bits number
+pub const MAX_VARINT_LENGTH: usize = 10;
+
+/// Largest number `N` bytes can carry
+pub const fn max_value<const N: usize>() -> usize {
+    match N {
+        0 => 0,
+        _ if N >= usize::BITS as usize / 8 => usize::MAX,
+        _ => (1 << (N * 8)) - 1,
+    }
+}
+
+/// Writes the number in `N` bytes big endian, fails with
+/// [`MtpError::OversizedLength`] when it does not fit
+pub fn encode<const N: usize>(value: usize) -> Result<[u8; N]> {
+    if value > max_value::<N>() {
+        return Err(MtpError::OversizedLength {
+            length: value,
+            max: max_value::<N>(),
+        });
+    }
+
+    let mut bytes = [0; N];
+    let value_bytes = (value as u64).to_be_bytes();
+
+    // Widths above 8 bytes are left padded with zeros
+    let width = N.min(value_bytes.len());
+    bytes[N - width..].copy_from_slice(&value_bytes[value_bytes.len() - width..]);
+
+    Ok(bytes)
+}
+
+/// Reads a number written by [`encode`], fails with
+/// [`MtpError::MalformedHeader`] when it does not fit a `usize`
+pub fn decode<const N: usize>(bytes: [u8; N]) -> Result<usize> {
+    bytes.iter().try_fold(0usize, |value, byte| {
+        value
+            .checked_mul(256)
+            .map(|value| value + *byte as usize)
+            .ok_or(MtpError::MalformedHeader("number does not fit in usize"))
+    })
+}
+
+/// Reads `N` bytes from the stream and decodes them, see [`decode`]
+pub fn read<R: Read, const N: usize>(stream: &mut R) -> Result<usize> {
+    decode(read_array::<R, N>(stream)?)
+}
+
+/// Writes the number as a LEB128 varint, 7 bits per byte starting
+/// from the lowest ones, the high bit of a byte set when more follow
+pub fn encode_varint(mut value: usize) -> Vec<u8> {
+    let mut bytes = vec![];
+
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Reads a varint written by [`encode_varint`], fails with [`MtpError::MalformedHeader`]
+/// when it is longer than [`MAX_VARINT_LENGTH`] or does not fit a `usize`
+pub fn read_varint<R: Read>(stream: &mut R) -> Result<usize> {
+    let mut value = 0usize;
+
+    for i in 0..MAX_VARINT_LENGTH {
+        let [byte] = read_array::<R, 1>(stream)?;
+        let bits = (byte & 0x7f) as usize;
+        let shift = i as u32 * 7;
+
+        // Bits shifted past the width of usize would be lost
+        if shift >= usize::BITS || (bits << shift) >> shift != bits {
+            return Err(MtpError::MalformedHeader("varint does not fit in usize"));
+        }
+
+        value |= bits << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(MtpError::MalformedHeader("varint is too long"))
+}
+
+/// Same as [`read_varint`] over a slice, returns the number and how many bytes it took
+pub fn decode_varint(bytes: &[u8]) -> Result<(usize, usize)> {
+    let mut cursor = bytes;
+    let value = read_varint(&mut cursor)?;
+
+    Ok((value, bytes.len() - cursor.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use crate::{
+        codec::{decode, decode_varint, encode, encode_varint, max_value, read, read_varint},
+        error::MtpError,
+        tests::Trickle,
+    };
+
+    /// Edge values and random values of every magnitude below the maximum
+    fn samples(max: usize) -> Vec<usize> {
+        let mut rng = rand::thread_rng();
+        let mut samples = vec![0, 1, 127, 128, 255, 256, max - 1, max];
+
+        for _ in 0..1000 {
+            let bits = rng.gen_range(0..=max.count_ones());
+            samples
+                .push(rng.gen::<usize>() & max.checked_shr(max.count_ones() - bits).unwrap_or(0));
+        }
+
+        samples
+    }
+
+    fn round_trip<const N: usize>() {
+        for value in samples(max_value::<N>()) {
+            let bytes = encode::<N>(value).unwrap();
+
+            assert_eq!(decode(bytes).unwrap(), value);
+            assert_eq!(read::<_, N>(&mut bytes.as_slice()).unwrap(), value);
+        }
+
+        if max_value::<N>() < usize::MAX {
+            assert!(matches!(
+                encode::<N>(max_value::<N>() + 1),
+                Err(MtpError::OversizedLength { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn fixed_width_round_trip() {
+        round_trip::<2>();
+        round_trip::<3>();
+        round_trip::<4>();
+        round_trip::<8>();
+    }
+
+    #[test]
+    fn fixed_width_is_big_endian() {
+        assert_eq!(encode::<2>(7000).unwrap(), [0x1b, 0x58]);
+        assert_eq!(encode::<3>(0x0a0b0c).unwrap(), [0x0a, 0x0b, 0x0c]);
+        assert_eq!(encode::<4>(0xdeadbeef).unwrap(), [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(encode::<8>(1).unwrap(), [0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(max_value::<3>(), usize::pow(2, 24) - 1);
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for value in samples(usize::MAX) {
+            let bytes = encode_varint(value);
+
+            assert_eq!(decode_varint(&bytes).unwrap(), (value, bytes.len()));
+            assert_eq!(
+                read_varint(&mut Trickle(std::io::Cursor::new(bytes))).unwrap(),
+                value
+            );
+        }
+
+        assert_eq!(encode_varint(0), [0]);
+        assert_eq!(encode_varint(300), [0xac, 0x02]);
+    }
+
+    #[test]
+    fn malformed_varints() {
+        // Never ending
+        assert!(matches!(
+            decode_varint(&[0xff; 11]),
+            Err(MtpError::MalformedHeader(_))
+        ));
+
+        // Above 64 bits
+        let mut overflowing = vec![0xff; 9];
+        overflowing.push(0x7f);
+        assert!(matches!(
+            decode_varint(&overflowing),
+            Err(MtpError::MalformedHeader(_))
+        ));
+
+        // Cut short
+        assert!(matches!(decode_varint(&[0x80]), Err(MtpError::PeerAborted)));
+    }
+}
@@ -0,0 +1,228 @@
+use std::fmt;
+
+use openssl::{
+    hash::MessageDigest,
+    pkey::{PKey, Private, Public},
+    rsa::{Padding, Rsa},
+    sha::Sha256,
+    sign::{Signer, Verifier},
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use rand::Rng;
+
+use crate::error::{MtpError, Result};
+
+/// Bytes taken by OAEP padding out of every RSA block
+const OAEP_PADDING_SIZE: usize = 42;
+
+/// Length of the AES-256-GCM session key
+pub const SESSION_KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+/// Bytes added to every piece of data sealed with a [`SessionKey`],
+/// `[12 bytes nonce][encrypted data][16 bytes tag]`
+pub const SESSION_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+/// Encrypts the data with the public key of the other party.
+///
+/// RSA can only encrypt a limited amount of bytes at once, so
+/// the data is split into blocks of `key size - 42` bytes and every
+/// block is encrypted separately into a block of `key size` bytes.
+pub fn encrypt(public_key: &PKey<Public>, data: &[u8]) -> Result<Vec<u8>> {
+    let rsa = public_key.rsa()?;
+    let block_size = rsa.size() as usize;
+
+    let mut encrypted = vec![];
+
+    for chunk in data.chunks(block_size - OAEP_PADDING_SIZE) {
+        let mut block = vec![0; block_size];
+        let length = rsa.public_encrypt(chunk, &mut block, Padding::PKCS1_OAEP)?;
+
+        encrypted.extend_from_slice(&block[..length]);
+    }
+
+    Ok(encrypted)
+}
+
+/// Decrypts data encrypted by [`encrypt`] using our own private key.
+pub fn decrypt(private_key: &Rsa<Private>, data: &[u8]) -> Result<Vec<u8>> {
+    let block_size = private_key.size() as usize;
+
+    if !data.len().is_multiple_of(block_size) {
+        return Err(MtpError::MalformedHeader(
+            "encrypted data length is not a multiple of the key size",
+        ));
+    }
+
+    let mut decrypted = vec![];
+
+    for chunk in data.chunks(block_size) {
+        let mut block = vec![0; block_size];
+        let length = private_key.private_decrypt(chunk, &mut block, Padding::PKCS1_OAEP)?;
+
+        decrypted.extend_from_slice(&block[..length]);
+    }
+
+    Ok(decrypted)
+}
+
+/// Signs the data with our own private key using RSA with SHA-256
+pub fn sign(private_key: &Rsa<Private>, data: &[u8]) -> Result<Vec<u8>> {
+    let private_key = PKey::from_rsa(private_key.clone())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &private_key)?;
+    signer.update(data)?;
+
+    Ok(signer.sign_to_vec()?)
+}
+
+/// Checks a signature made by [`sign`] with the public key of the other party
+pub fn verify(public_key: &PKey<Public>, data: &[u8], signature: &[u8]) -> Result<bool> {
+    let mut verifier = Verifier::new(MessageDigest::sha256(), public_key)?;
+    verifier.update(data)?;
+
+    Ok(verifier.verify(signature)?)
+}
+
+/// Random half of a session key, each party generates one and
+/// sends it to the other wrapped with the other's public key
+pub fn generate_key_share() -> [u8; SESSION_KEY_SIZE] {
+    rand::thread_rng().gen()
+}
+
+/// Symmetric AES-256-GCM key agreed by both parties during the handshake,
+/// used to encrypt package data without paying for RSA on every block.
+#[derive(Clone, PartialEq)]
+pub struct SessionKey([u8; SESSION_KEY_SIZE]);
+
+impl SessionKey {
+    /// Derives the session key from both key shares, the shares are
+    /// ordered before hashing so both parties compute the same key.
+    pub fn derive(own_share: &[u8], peer_share: &[u8]) -> Self {
+        let (first, second) = if own_share <= peer_share {
+            (own_share, peer_share)
+        } else {
+            (peer_share, own_share)
+        };
+
+        let mut hasher = Sha256::new();
+        hasher.update(first);
+        hasher.update(second);
+
+        Self(hasher.finish())
+    }
+
+    /// Encrypts and authenticates the data along with the additional data `aad`,
+    /// the result is [`SESSION_OVERHEAD`] bytes longer than the data.
+    pub fn seal(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let nonce: [u8; NONCE_SIZE] = rand::thread_rng().gen();
+        let mut tag = [0; TAG_SIZE];
+
+        let encrypted = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.0,
+            Some(&nonce),
+            aad,
+            data,
+            &mut tag,
+        )?;
+
+        let mut sealed = Vec::with_capacity(data.len() + SESSION_OVERHEAD);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&encrypted);
+        sealed.extend_from_slice(&tag);
+
+        Ok(sealed)
+    }
+
+    /// Decrypts data sealed by [`SessionKey::seal`], fails with [`MtpError::Crypto`]
+    /// when the data or the additional data `aad` have been tampered with
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < SESSION_OVERHEAD {
+            return Err(MtpError::MalformedHeader(
+                "sealed data is shorter than the session overhead",
+            ));
+        }
+
+        let (nonce, rest) = sealed.split_at(NONCE_SIZE);
+        let (encrypted, tag) = rest.split_at(rest.len() - TAG_SIZE);
+
+        Ok(decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.0,
+            Some(nonce),
+            aad,
+            encrypted,
+            tag,
+        )?)
+    }
+}
+
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::{pkey::PKey, rsa::Rsa};
+
+    use crate::{
+        crypto::{
+            decrypt, encrypt, generate_key_share, sign, verify, SessionKey, SESSION_OVERHEAD,
+        },
+        error::MtpError,
+    };
+
+    #[test]
+    fn encrypt_decrypt_multiple_blocks() {
+        let key = Rsa::generate(2048).unwrap();
+        let public_key = PKey::public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap();
+
+        let data = (0..1000).map(|i| (i % 256) as u8).collect::<Vec<u8>>();
+        let encrypted = encrypt(&public_key, &data).unwrap();
+
+        assert_ne!(encrypted, data);
+        assert!(encrypted.len().is_multiple_of(key.size() as usize));
+        assert_eq!(decrypt(&key, &encrypted).unwrap(), data);
+    }
+
+    #[test]
+    fn sign_verify() {
+        let key = Rsa::generate(2048).unwrap();
+        let public_key = PKey::public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap();
+
+        let signature = sign(&key, b"challenge").unwrap();
+
+        assert!(verify(&public_key, b"challenge", &signature).unwrap());
+        assert!(!verify(&public_key, b"other challenge", &signature).unwrap());
+    }
+
+    #[test]
+    fn session_key_seal_open() {
+        let client_share = generate_key_share();
+        let server_share = generate_key_share();
+
+        let client_key = SessionKey::derive(&client_share, &server_share);
+        let server_key = SessionKey::derive(&server_share, &client_share);
+        assert_eq!(client_key, server_key);
+
+        let data = (0..1000).map(|i| (i % 256) as u8).collect::<Vec<u8>>();
+        let sealed = client_key.seal(b"header", &data).unwrap();
+
+        assert_eq!(sealed.len(), data.len() + SESSION_OVERHEAD);
+        assert_eq!(server_key.open(b"header", &sealed).unwrap(), data);
+    }
+
+    #[test]
+    fn session_key_rejects_tampered_header() {
+        let key = SessionKey::derive(&generate_key_share(), &generate_key_share());
+        let sealed = key.seal(b"header", b"some data").unwrap();
+
+        assert!(matches!(
+            key.open(b"HEADER", &sealed),
+            Err(MtpError::Crypto(_))
+        ));
+    }
+}
@@ -0,0 +1,160 @@
+use std::{fmt, io};
+
+use openssl::error::ErrorStack;
+
+use crate::trust::Fingerprint;
+
+/// Errors produced while speaking the protocol with another party
+#[derive(Debug)]
+pub enum MtpError {
+    /// The underlying stream failed
+    Io(io::Error),
+
+    /// The other party closed the connection or asked to stop
+    PeerAborted,
+
+    /// The receiver cancelled the transfer through [`crate::package::control::TransferControl`]
+    Cancelled,
+
+    /// A header received from the other party does not follow the protocol
+    MalformedHeader(&'static str),
+
+    /// The other party speaks a version of the protocol too old to be understood
+    UnsupportedVersion(u8),
+
+    /// Both parties have no capability in common required to communicate
+    Incompatible(&'static str),
+
+    /// A custom batch size of 0 packages,
+    /// see [`crate::package::packages::PackagesBatchSize::CUSTOM`]
+    InvalidBatchSize(u8),
+
+    /// A custom package size not between 1 and [`crate::utils::MAX_EXTENDED_LENGTH`]
+    /// bytes, see [`crate::package::PackageSize::CUSTOM`]
+    InvalidPackageSize(usize),
+
+    /// A public key received during the handshake or a private key
+    /// loaded by [`crate::identity::Identity`] is not a valid PEM
+    BadPem(ErrorStack),
+
+    /// A length does not fit in the header meant to carry it
+    OversizedLength { length: usize, max: usize },
+
+    /// The public key of the other party is not in the trust store
+    UntrustedPeer(Fingerprint),
+
+    /// Only a [`crate::trust::TrustStore::PINNED`] store has fingerprints to be saved
+    NotPinned,
+
+    /// The other party failed to prove it owns the private key of its public key
+    BadSignature,
+
+    /// The validator of the handshake rejected the payload sent by the other party
+    PayloadRejected,
+
+    /// Encryption was requested on a stream which has not been handshaken
+    Unshaken,
+
+    /// A package expected to be encrypted is not marked as encrypted
+    NotEncrypted,
+
+    /// The data of the package with that item number does not match its checksum
+    ChecksumMismatch { item: usize },
+
+    /// The data of a whole transfer does not match the digest sent after it
+    DigestMismatch,
+
+    /// The channel of a [`crate::multiplex::Multiplexer`] is already open
+    ChannelInUse(u32),
+
+    /// Messages of the channel of a [`crate::multiplex::Multiplexer`] were dropped
+    /// because the channel was not opened before its queue was full
+    MessagesDropped(u32),
+
+    /// The other party sent packages on more channels not opened yet
+    /// than a [`crate::multiplex::Multiplexer`] keeps
+    TooManyChannels(usize),
+
+    /// Encrypting or decrypting data failed, either the keys do not match
+    /// or the data has been tampered with
+    Crypto(ErrorStack),
+}
+
+pub type Result<T> = std::result::Result<T, MtpError>;
+
+impl fmt::Display for MtpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "io error: {}", err),
+            Self::PeerAborted => write!(f, "peer aborted the connection"),
+            Self::Cancelled => write!(f, "transfer was cancelled by the receiver"),
+            Self::MalformedHeader(header) => write!(f, "malformed header: {}", header),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            Self::Incompatible(reason) => write!(f, "incompatible peer: {}", reason),
+            Self::InvalidBatchSize(value) => write!(f, "invalid batch size {}", value),
+            Self::InvalidPackageSize(value) => write!(f, "invalid package size {}", value),
+            Self::BadPem(err) => write!(f, "bad key pem: {}", err),
+            Self::OversizedLength { length, max } => {
+                write!(f, "length {} is over the maximum of {}", length, max)
+            }
+            Self::UntrustedPeer(fingerprint) => write!(f, "untrusted peer {}", fingerprint),
+            Self::NotPinned => write!(f, "trust store has no pinned fingerprints"),
+            Self::BadSignature => write!(f, "peer failed to sign the handshake challenge"),
+            Self::PayloadRejected => write!(f, "peer handshake payload was rejected"),
+            Self::Unshaken => write!(f, "stream has not been handshaken"),
+            Self::NotEncrypted => write!(f, "package is not marked as encrypted"),
+            Self::ChecksumMismatch { item } => write!(f, "package {} failed its checksum", item),
+            Self::DigestMismatch => write!(f, "transfer does not match its digest"),
+            Self::ChannelInUse(channel) => write!(f, "channel {} is already open", channel),
+            Self::MessagesDropped(channel) => {
+                write!(f, "messages of channel {} were dropped", channel)
+            }
+            Self::TooManyChannels(max) => {
+                write!(f, "more than {} channels are waiting to be opened", max)
+            }
+            Self::Crypto(err) => write!(f, "encryption error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MtpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::BadPem(err) | Self::Crypto(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MtpError {
+    /// A closed connection is reported as [`MtpError::PeerAborted`]
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => Self::PeerAborted,
+            _ => Self::Io(err),
+        }
+    }
+}
+
+impl From<ErrorStack> for MtpError {
+    fn from(err: ErrorStack) -> Self {
+        Self::Crypto(err)
+    }
+}
+
+impl From<MtpError> for io::Error {
+    /// Lets protocol errors surface through [`std::io::Read`] and [`std::io::Write`] adapters
+    fn from(err: MtpError) -> Self {
+        match err {
+            MtpError::Io(err) => err,
+            MtpError::PeerAborted => io::Error::new(io::ErrorKind::UnexpectedEof, err),
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::{fs, io::Write, path::Path};
+
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+use openssl::{
+    pkey::{PKey, Private, Public},
+    rsa::Rsa,
+    symm::Cipher,
+};
+
+use crate::{
+    error::{MtpError, Result},
+    trust::Fingerprint,
+};
+
+/// Private key identifying one party across sessions, its public key
+/// is the one sent in the [`crate::shake::Shake`] and pinned by other parties.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    private_key: Rsa<Private>,
+}
+
+impl Identity {
+    /// Generates a new 2048 bits RSA identity
+    pub fn generate() -> Result<Self> {
+        Ok(Self {
+            private_key: Rsa::generate(2048)?,
+        })
+    }
+
+    pub fn from_private_key(private_key: Rsa<Private>) -> Self {
+        Self { private_key }
+    }
+
+    /// Reads a PEM encoded RSA private key, the passphrase
+    /// is required when the key was saved with one
+    pub fn from_pem(pem: &[u8], passphrase: Option<&[u8]>) -> Result<Self> {
+        let private_key = match passphrase {
+            Some(passphrase) => Rsa::private_key_from_pem_passphrase(pem, passphrase),
+            None => Rsa::private_key_from_pem(pem),
+        }
+        .map_err(MtpError::BadPem)?;
+
+        Ok(Self { private_key })
+    }
+
+    /// PEM encoded private key, encrypted with AES-256-CBC when a passphrase is given
+    pub fn to_pem(&self, passphrase: Option<&[u8]>) -> Result<Vec<u8>> {
+        Ok(match passphrase {
+            Some(passphrase) => self
+                .private_key
+                .private_key_to_pem_passphrase(Cipher::aes_256_cbc(), passphrase)?,
+            None => self.private_key.private_key_to_pem()?,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, passphrase: Option<&[u8]>) -> Result<Self> {
+        Self::from_pem(&fs::read(path).map_err(MtpError::Io)?, passphrase)
+    }
+
+    /// Writes the PEM encoded private key, on unix the file is
+    /// left readable and writable by its owner only
+    pub fn save<P: AsRef<Path>>(&self, path: P, passphrase: Option<&[u8]>) -> Result<()> {
+        let pem = self.to_pem(passphrase)?;
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        let mut file = options.open(path).map_err(MtpError::Io)?;
+
+        // The mode only applies to new files, an existing one keeps its own
+        #[cfg(unix)]
+        file.set_permissions(fs::Permissions::from_mode(0o600))
+            .map_err(MtpError::Io)?;
+
+        file.write_all(&pem).map_err(MtpError::Io)
+    }
+
+    pub fn private_key(&self) -> &Rsa<Private> {
+        &self.private_key
+    }
+
+    pub fn public_key(&self) -> Result<PKey<Public>> {
+        Ok(PKey::public_key_from_pem(
+            &self.private_key.public_key_to_pem()?,
+        )?)
+    }
+
+    /// Fingerprint other parties pin in their [`crate::trust::TrustStore`]
+    pub fn fingerprint(&self) -> Result<Fingerprint> {
+        Fingerprint::of(&self.public_key()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::MtpError, identity::Identity};
+
+    #[test]
+    fn save_load_with_passphrase() {
+        let path = std::env::temp_dir().join(format!("mtp_identity_{}.pem", std::process::id()));
+
+        let identity = Identity::generate().unwrap();
+        identity.save(&path, Some(b"secret")).unwrap();
+
+        let loaded = Identity::load(&path, Some(b"secret")).unwrap();
+        let wrong_passphrase = Identity::load(&path, Some(b"wrong"));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            identity.fingerprint().unwrap(),
+            loaded.fingerprint().unwrap()
+        );
+        assert!(matches!(wrong_passphrase, Err(MtpError::BadPem(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saved_key_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path =
+            std::env::temp_dir().join(format!("mtp_identity_mode_{}.pem", std::process::id()));
+
+        // Overwritten files are restricted too
+        std::fs::write(&path, b"").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        Identity::generate().unwrap().save(&path, None).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn pem_without_passphrase() {
+        let identity = Identity::generate().unwrap();
+        let pem = identity.to_pem(None).unwrap();
+
+        let loaded = Identity::from_pem(&pem, None).unwrap();
+
+        assert_eq!(
+            identity.fingerprint().unwrap(),
+            loaded.fingerprint().unwrap()
+        );
+    }
+}
@@ -1,5 +1,16 @@
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+pub mod capabilities;
+pub mod codec;
+pub mod crypto;
+pub mod error;
+pub mod identity;
+pub mod listener;
+pub mod multiplex;
+pub mod rpc;
 pub mod shake;
 pub mod stream;
+pub mod trust;
 pub mod bufferable;
 pub mod utils;
 pub mod package;
@@ -16,9 +27,12 @@ pub mod package;
 
 // For method used widely
 pub mod tests {
-    use std::net::{TcpListener, TcpStream};
+    use std::{
+        io::{Cursor, Read},
+        net::{TcpListener, TcpStream},
+    };
 
-    use crate::{stream::Stream, shake::Handshake};
+    use crate::{capabilities::Capabilities, shake::HandshakeBuilder, stream::Stream};
 
 
     pub struct Connected {
@@ -33,25 +47,47 @@ pub mod tests {
         }
     }
 
+    /// Hands out a single byte per read like a slow link would
+    pub struct Trickle(pub Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let length = buf.len().min(1);
+            self.0.read(&mut buf[..length])
+        }
+    }
+
     pub fn stablish_server_client_connection() -> Connected {
-        let server = TcpListener::bind("127.0.0.1:4645").unwrap();
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
 
-        let client_tcp_stream = TcpStream::connect("127.0.0.1:4645").unwrap();
+        let client_tcp_stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
         let (server_tcp_stream, _) = server.accept().unwrap();
 
-        let client_stream = Stream {
-            handshaken: Handshake::UNSHAKEN,
-            tcp_stream: client_tcp_stream,
-        };
-
-        let server_stream = Stream {
-            handshaken: Handshake::UNSHAKEN,
-            tcp_stream: server_tcp_stream,
-        };
+        let client_stream = Stream::unshaken(client_tcp_stream);
+        let server_stream = Stream::unshaken(server_tcp_stream);
 
         Connected {
             server: server_stream,
             client: client_stream
         }
     }
+
+    /// Both ends of a connection handshaken with the same capabilities, (Server, Client)
+    pub fn handshaken_pair(capabilities: Capabilities) -> (Stream, Stream) {
+        let (server, client) = stablish_server_client_connection().split();
+
+        let server = std::thread::spawn(move || {
+            HandshakeBuilder::new()
+                .capabilities(capabilities)
+                .connect_stream(server.tcp_stream)
+                .unwrap()
+        });
+
+        let client = HandshakeBuilder::new()
+            .capabilities(capabilities)
+            .connect_stream(client.tcp_stream)
+            .unwrap();
+
+        (server.join().unwrap(), client)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,314 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{MtpError, Result},
+    identity::Identity,
+    shake::HandshakeBuilder,
+    stream::Stream,
+};
+
+/// Accepts connections and performs the handshake with every one of them
+/// before handing them out as ready [`Stream`]s
+/// ```ignore
+/// let listener = MtpListener::bind("127.0.0.1:3400")?;
+///
+/// for stream in listener.incoming() {
+///     let packages = stream?.receive_packages()?;
+/// }
+/// ```
+///
+/// Handshakes performed by [`MtpListener::accept`] and [`MtpListener::incoming`] run
+/// one at a time on the calling thread, so a slow connection delays the next ones
+/// for up to the handshake timeout. [`MtpListener::accept_pending`] hands out the
+/// connection before its handshake so it can be performed on another thread
+/// ```ignore
+/// loop {
+///     let (pending, _) = listener.accept_pending()?;
+///
+///     std::thread::spawn(move || {
+///         let packages = pending.handshake()?.receive_packages()?;
+///     });
+/// }
+/// ```
+#[derive(Debug)]
+pub struct MtpListener {
+    tcp_listener: TcpListener,
+    handshake: HandshakeBuilder,
+    handshake_timeout: Option<Duration>,
+    /// Generated when bound, used by handshakes configured without an identity
+    identity: Identity,
+}
+
+impl MtpListener {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let identity = Identity::generate()?;
+
+        Ok(Self {
+            tcp_listener: TcpListener::bind(addr).map_err(MtpError::Io)?,
+            handshake: HandshakeBuilder::new().identity(identity.clone()),
+            handshake_timeout: Some(Duration::from_secs(10)),
+            identity,
+        })
+    }
+
+    /// Handshake performed with every accepted connection, the identity
+    /// generated when the listener was bound is used when it has none
+    pub fn set_handshake(&mut self, handshake: HandshakeBuilder) {
+        self.handshake = if handshake.has_identity() {
+            handshake
+        } else {
+            handshake.identity(self.identity.clone())
+        };
+    }
+
+    /// Time a connection has to complete the whole handshake, 10 seconds by default.
+    /// `None` waits forever, which lets a silent connection block the listener
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) {
+        self.handshake_timeout = timeout;
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.tcp_listener.local_addr().map_err(MtpError::Io)
+    }
+
+    /// Waits for a connection and performs the handshake with it,
+    /// the connection is closed when the handshake fails
+    pub fn accept(&self) -> Result<(Stream, SocketAddr)> {
+        let (pending, addr) = self.accept_pending()?;
+
+        Ok((pending.handshake()?, addr))
+    }
+
+    /// Waits for a connection without performing the handshake,
+    /// see [`PendingHandshake::handshake`]
+    pub fn accept_pending(&self) -> Result<(PendingHandshake, SocketAddr)> {
+        let (tcp_stream, addr) = self.tcp_listener.accept().map_err(MtpError::Io)?;
+
+        Ok((
+            PendingHandshake {
+                tcp_stream,
+                handshake: self.handshake.clone(),
+                handshake_timeout: self.handshake_timeout,
+            },
+            addr,
+        ))
+    }
+
+    /// Iterator over handshaken connections, connections whose handshake
+    /// fails or times out are rejected and skipped, only errors of the
+    /// listener itself are returned
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+}
+
+/// Connection accepted by [`MtpListener::accept_pending`] whose handshake is yet to be performed
+#[derive(Debug)]
+pub struct PendingHandshake {
+    tcp_stream: TcpStream,
+    handshake: HandshakeBuilder,
+    handshake_timeout: Option<Duration>,
+}
+
+impl PendingHandshake {
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self.tcp_stream.peer_addr().map_err(MtpError::Io)
+    }
+
+    /// Performs the handshake configured on the listener, fails with a
+    /// [`io::ErrorKind::TimedOut`] error once the handshake timeout is over
+    pub fn handshake(self) -> Result<Stream> {
+        let Some(timeout) = self.handshake_timeout else {
+            return self.handshake.connect_stream(self.tcp_stream);
+        };
+
+        let mut tcp_stream = self.tcp_stream;
+
+        let (handshaken, capabilities) = self.handshake.negotiate(&mut Deadline {
+            tcp_stream: &mut tcp_stream,
+            deadline: Instant::now() + timeout,
+        })?;
+
+        tcp_stream.set_read_timeout(None).map_err(MtpError::Io)?;
+        tcp_stream.set_write_timeout(None).map_err(MtpError::Io)?;
+
+        Ok(Stream {
+            tcp_stream,
+            handshaken,
+            capabilities,
+        })
+    }
+}
+
+/// Tcp stream whose reads and writes time out once the deadline is over,
+/// socket timeouts alone are reset by every byte the other party sends
+struct Deadline<'a> {
+    tcp_stream: &'a mut TcpStream,
+    deadline: Instant,
+}
+
+impl Deadline<'_> {
+    fn remaining(&self) -> io::Result<Duration> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+
+        // A zero timeout is refused by the socket
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "handshake timed out",
+            ));
+        }
+
+        Ok(remaining)
+    }
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.tcp_stream.set_read_timeout(Some(self.remaining()?))?;
+        self.tcp_stream.read(buf)
+    }
+}
+
+impl Write for Deadline<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tcp_stream.set_write_timeout(Some(self.remaining()?))?;
+        self.tcp_stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tcp_stream.flush()
+    }
+}
+
+/// See [`MtpListener::incoming`]
+#[derive(Debug)]
+pub struct Incoming<'a> {
+    listener: &'a MtpListener,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = Result<Stream>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let pending = match self.listener.accept_pending() {
+                Ok((pending, _)) => pending,
+                Err(err) => return Some(Err(err)),
+            };
+
+            if let Ok(stream) = pending.handshake() {
+                return Some(Ok(stream));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::TcpStream,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        error::MtpError, identity::Identity, listener::MtpListener, stream::Stream,
+        trust::Fingerprint,
+    };
+
+    #[test]
+    fn accept_handshaken_stream() {
+        let listener = MtpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || Stream::connect(addr).unwrap());
+
+        let server_stream = listener.incoming().next().unwrap().unwrap();
+        let client_stream = client.join().unwrap();
+
+        assert!(server_stream.peer_payload().is_some());
+        assert!(client_stream.peer_payload().is_some());
+    }
+
+    #[test]
+    fn failed_handshakes_are_rejected() {
+        let mut listener = MtpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_handshake_timeout(Some(Duration::from_millis(500)));
+        let addr = listener.local_addr().unwrap();
+
+        // Generating a key can take longer than the whole handshake timeout
+        let handshake = Stream::handshake().identity(Identity::generate().unwrap());
+
+        let client = std::thread::spawn(move || {
+            // Not an MTP peer
+            let mut garbage = TcpStream::connect(addr).unwrap();
+            garbage.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+
+            // Never sends its shake
+            let silent = TcpStream::connect(addr).unwrap();
+
+            let stream = handshake.connect(addr).unwrap();
+            drop((garbage, silent));
+            stream
+        });
+
+        let server_stream = listener.incoming().next().unwrap().unwrap();
+        client.join().unwrap();
+
+        assert!(server_stream.peer_payload().is_some());
+    }
+
+    #[test]
+    fn trickling_handshake_times_out() {
+        let mut listener = MtpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_handshake_timeout(Some(Duration::from_millis(300)));
+        let addr = listener.local_addr().unwrap();
+
+        // Sends the start of a shake one byte at a time, each byte well within the timeout
+        let client = std::thread::spawn(move || {
+            let mut trickle = TcpStream::connect(addr).unwrap();
+
+            for byte in b"MTP\x00\x01\x00\x00\x00\x01" {
+                std::thread::sleep(Duration::from_millis(100));
+                if trickle.write_all(&[*byte]).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let started = Instant::now();
+        let (pending, _) = listener.accept_pending().unwrap();
+        let handshake = pending.handshake();
+        client.join().unwrap();
+
+        assert!(matches!(handshake, Err(MtpError::Io(_))));
+        assert!(started.elapsed() < Duration::from_millis(800));
+    }
+
+    #[test]
+    fn handshakes_share_the_listener_identity() {
+        let listener = MtpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let clients = std::thread::spawn(move || {
+            [(); 2].map(|_| {
+                let stream = Stream::connect(addr).unwrap();
+                Fingerprint::of(&stream.handshaken.peer_shake().unwrap().public_key).unwrap()
+            })
+        });
+
+        for _ in 0..2 {
+            let (pending, _) = listener.accept_pending().unwrap();
+            std::thread::spawn(move || pending.handshake().unwrap());
+        }
+
+        let [first, second] = clients.join().unwrap();
+
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,722 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{
+    capabilities::{capability, Capabilities},
+    crypto::SessionKey,
+    error::{MtpError, Result},
+    package::{
+        max_sealed_length,
+        packages::{
+            digest_length, footer, Chunks, Packages, PackagesBatchSize, PackagesReceiver,
+            PackagesSender,
+        },
+        Package,
+    },
+    stream::{check_packages, Stream},
+    utils::{read_array, read_bytes},
+};
+
+/// Packages kept for a channel until it reads them, once full the connection stops
+/// being read until the channel catches up, or the queued messages are dropped when
+/// the channel is not open
+const CHANNEL_QUEUE: usize = 64;
+
+/// Channels not opened yet the other party may send packages on
+/// before the connection is refused
+const PENDING_CHANNELS: usize = 64;
+
+/// How often a full queue is retried while its channel catches up
+const CHANNEL_QUEUE_RETRY: Duration = Duration::from_millis(5);
+
+/// Carries several transfers over a single [`Stream`] at once, every package
+/// is marked with the channel it belongs to ([`crate::package::meta::PackageMeta::channel`])
+/// so transfers interleave package by package.
+///
+/// Channels are known by both parties by their identifier, packages received
+/// for a channel not opened yet wait for it to be opened, up to 64 packages
+/// after which every message queued for the channel is dropped and opening it
+/// fails once with [`MtpError::MessagesDropped`]. Once [`Multiplexer::accept`] is called
+/// channels are accepted as soon as their first package is received, their packages
+/// are never dropped. Packages received on more than 64 channels not opened or
+/// accepted yet close the connection with [`MtpError::TooManyChannels`].
+/// Channels are not acknowledged by the receiver, an open channel which is
+/// not read eventually stops every other channel from being read, and
+/// adaptive package and batch sizes keep their starting values.
+///
+/// Dropping the multiplexer closes the connection like [`Multiplexer::shutdown`].
+/// ```ignore
+/// let multiplexer = Stream::connect("127.0.0.1:3400")?.multiplex()?;
+///
+/// let mut control = multiplexer.channel(0)?;
+/// let mut music = multiplexer.channel(1)?;
+///
+/// std::thread::spawn(move || music.send_from_reader(Packages::new(vec![]), file, None));
+/// control.send(b"play".to_vec())?;
+/// ```
+#[derive(Debug)]
+pub struct Multiplexer {
+    shared: Arc<Shared>,
+    /// Taken once the connection is closed
+    demultiplexer: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    writer: Mutex<TcpStream>,
+    session_key: SessionKey,
+    capabilities: Capabilities,
+    channels: Mutex<Channels>,
+    /// Channels the other party sent packages on, waiting to be accepted
+    incoming: Mutex<Receiver<Accepted>>,
+    /// Set when the multiplexer closes the connection, stops waiting for full queues
+    closing: AtomicBool,
+}
+
+/// Identifier and queue of a channel accepted by the demultiplexer
+type Accepted = (u32, Receiver<Frame>);
+
+#[derive(Debug, Default)]
+struct Channels {
+    queues: HashMap<u32, ChannelQueue>,
+    /// Hands channels to [`Multiplexer::accept`], dropped once the connection is closed
+    incoming: Option<SyncSender<Accepted>>,
+    /// Set once channels are accepted, new channels are then accepted on their first package
+    accepting: bool,
+    /// Channels whose message is dropped until its last package
+    dropping: HashSet<u32>,
+    /// Channels whose queued messages were dropped, reported once they are opened
+    dropped: HashSet<u32>,
+    closed: bool,
+    /// Why the connection stopped being read, taken by the first channel noticing it
+    error: Option<MtpError>,
+}
+
+impl Channels {
+    fn closed_error(&mut self) -> MtpError {
+        self.error.take().unwrap_or(MtpError::PeerAborted)
+    }
+
+    /// Adds the queue of a channel the other party sent its first package on,
+    /// fails when too many channels are waiting to be opened or accepted
+    fn register(&mut self, id: u32) -> Result<()> {
+        let (sender, frames) = sync_channel(CHANNEL_QUEUE);
+
+        let receiver = if self.accepting {
+            let incoming = self.incoming.as_ref().ok_or(MtpError::PeerAborted)?;
+
+            // The channel counts as open from now on, its packages wait for accept
+            if let Err(TrySendError::Full(_)) = incoming.try_send((id, frames)) {
+                return Err(MtpError::TooManyChannels(PENDING_CHANNELS));
+            }
+
+            None
+        } else if self.pending() >= PENDING_CHANNELS {
+            return Err(MtpError::TooManyChannels(PENDING_CHANNELS));
+        } else {
+            Some(frames)
+        };
+
+        self.queues.insert(id, ChannelQueue { sender, receiver });
+
+        Ok(())
+    }
+
+    /// Accepts every channel not opened yet and the channels registered from now on
+    fn start_accepting(&mut self) {
+        if self.accepting {
+            return;
+        }
+
+        self.accepting = true;
+
+        let Some(incoming) = &self.incoming else {
+            return;
+        };
+
+        for (id, queue) in &mut self.queues {
+            if let Some(frames) = queue.receiver.take() {
+                // Never full, there are fewer channels not opened than it holds
+                let _ = incoming.try_send((*id, frames));
+            }
+        }
+    }
+
+    /// Channels holding packages or dropped messages nobody opened them for
+    fn pending(&self) -> usize {
+        let unopened = self
+            .queues
+            .values()
+            .filter(|queue| queue.receiver.is_some());
+
+        unopened.count() + self.dropped.len()
+    }
+}
+
+#[derive(Debug)]
+struct ChannelQueue {
+    sender: SyncSender<Frame>,
+    /// Handed out once to the channel opened with the identifier
+    receiver: Option<Receiver<Frame>>,
+}
+
+impl ChannelQueue {
+    fn new() -> Self {
+        let (sender, receiver) = sync_channel(CHANNEL_QUEUE);
+
+        Self {
+            sender,
+            receiver: Some(receiver),
+        }
+    }
+}
+
+/// Package as read from the connection along with its footer
+/// and the digest following the last package of a transfer
+#[derive(Debug)]
+pub(crate) struct Frame {
+    package: Package,
+    footer: u8,
+    digest: Vec<u8>,
+}
+
+impl Frame {
+    pub(crate) fn channel(&self) -> u32 {
+        self.package.meta.channel
+    }
+}
+
+impl Multiplexer {
+    /// Starts reading the stream from a background thread, fails with
+    /// [`MtpError::Incompatible`] when the other party can not multiplex
+    pub fn new(stream: Stream) -> Result<Self> {
+        Self::start(stream, false)
+    }
+
+    /// Same as [`Multiplexer::new`], channels are accepted from the first package
+    /// received as if [`Multiplexer::accept`] had been called
+    pub(crate) fn accepting(stream: Stream) -> Result<Self> {
+        Self::start(stream, true)
+    }
+
+    fn start(stream: Stream, accepting: bool) -> Result<Self> {
+        let session_key = stream.session_key()?.clone();
+        check_multiplexed(&stream.capabilities)?;
+
+        let reader = stream.tcp_stream.try_clone().map_err(MtpError::Io)?;
+        let (incoming_sender, incoming) = sync_channel(PENDING_CHANNELS);
+
+        let shared = Arc::new(Shared {
+            writer: Mutex::new(stream.tcp_stream),
+            session_key,
+            capabilities: stream.capabilities,
+            channels: Mutex::new(Channels {
+                incoming: Some(incoming_sender),
+                accepting,
+                ..Channels::default()
+            }),
+            incoming: Mutex::new(incoming),
+            closing: AtomicBool::new(false),
+        });
+
+        let demultiplexer = {
+            let shared = shared.clone();
+            std::thread::spawn(move || demultiplex(reader, &shared))
+        };
+
+        Ok(Self {
+            shared,
+            demultiplexer: Some(demultiplexer),
+        })
+    }
+
+    /// Opens the channel, fails with [`MtpError::ChannelInUse`]
+    /// while another handle of the channel is alive
+    pub fn channel(&self, id: u32) -> Result<Channel> {
+        let mut channels = lock(&self.shared.channels);
+
+        if channels.closed {
+            return Err(channels.closed_error());
+        }
+
+        if channels.dropped.remove(&id) {
+            return Err(MtpError::MessagesDropped(id));
+        }
+
+        let frames = channels
+            .queues
+            .entry(id)
+            .or_insert_with(ChannelQueue::new)
+            .receiver
+            .take()
+            .ok_or(MtpError::ChannelInUse(id))?;
+
+        Ok(Channel {
+            id,
+            shared: self.shared.clone(),
+            frames,
+        })
+    }
+
+    /// Waits for the other party to send packages on a channel which is not open yet
+    /// and opens it, fails once the connection is closed.
+    ///
+    /// Fails with [`MtpError::MessagesDropped`] for every channel whose messages were
+    /// dropped before the first call, channels are never dropped afterwards
+    pub fn accept(&self) -> Result<Channel> {
+        {
+            let mut channels = lock(&self.shared.channels);
+            channels.start_accepting();
+
+            if let Some(&id) = channels.dropped.iter().next() {
+                channels.dropped.remove(&id);
+                return Err(MtpError::MessagesDropped(id));
+            }
+        }
+
+        let (id, frames) = lock(&self.shared.incoming)
+            .recv()
+            .map_err(|_| lock(&self.shared.channels).closed_error())?;
+
+        Ok(Channel {
+            id,
+            shared: self.shared.clone(),
+            frames,
+        })
+    }
+
+    /// Closes the connection, channels still open fail with [`MtpError::PeerAborted`]
+    pub fn shutdown(mut self) -> Result<()> {
+        self.close()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let Some(demultiplexer) = self.demultiplexer.take() else {
+            return Ok(());
+        };
+
+        self.shared.closing.store(true, Ordering::Relaxed);
+
+        let shutdown = lock(&self.shared.writer)
+            .shutdown(Shutdown::Both)
+            .map_err(MtpError::Io);
+
+        // The thread only stops once the connection is closed and never panics
+        let _ = demultiplexer.join();
+
+        shutdown
+    }
+}
+
+impl Drop for Multiplexer {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+/// Routes every package read to the queue of its channel until the connection fails
+fn demultiplex(mut reader: TcpStream, shared: &Shared) {
+    let max_length = max_sealed_length(shared.capabilities.max_package_size);
+
+    let error = loop {
+        let frame = match read_frame(&mut reader, max_length) {
+            Ok(frame) => frame,
+            Err(error) => break error,
+        };
+
+        if let Err(error) = route(shared, frame) {
+            break error;
+        }
+    };
+
+    let mut channels = lock(&shared.channels);
+
+    // Dropping the senders wakes up the channels waiting for packages and accept
+    channels.queues.clear();
+    channels.incoming = None;
+    channels.closed = true;
+    channels.error = Some(error);
+}
+
+/// Hands the frame to the queue of its channel, waiting while the queue of an open
+/// channel is full, fails once the multiplexer closes the connection or too many
+/// channels are not opened
+fn route(shared: &Shared, mut frame: Frame) -> Result<()> {
+    let channel = frame.channel();
+
+    let sender = {
+        let mut channels = lock(&shared.channels);
+
+        if channels.dropping.contains(&channel) {
+            if frame.footer == footer::LAST {
+                channels.dropping.remove(&channel);
+            }
+
+            return Ok(());
+        }
+
+        if !channels.queues.contains_key(&channel) {
+            channels.register(channel)?;
+        }
+
+        channels.queues[&channel].sender.clone()
+    };
+
+    loop {
+        frame = match sender.try_send(frame) {
+            // The channel was dropped while the package was routed, nobody is left to read it
+            Ok(()) | Err(TrySendError::Disconnected(_)) => return Ok(()),
+            Err(TrySendError::Full(frame)) => frame,
+        };
+
+        let mut channels = lock(&shared.channels);
+
+        // Nobody opened the channel, its messages are dropped rather than stalling other channels
+        let unopened = channels
+            .queues
+            .get(&channel)
+            .is_some_and(|queue| queue.receiver.is_some());
+
+        if unopened {
+            channels.queues.remove(&channel);
+            channels.dropped.insert(channel);
+
+            if frame.footer != footer::LAST {
+                channels.dropping.insert(channel);
+            }
+
+            return Ok(());
+        }
+
+        drop(channels);
+
+        if shared.closing.load(Ordering::Relaxed) {
+            return Err(MtpError::PeerAborted);
+        }
+
+        std::thread::sleep(CHANNEL_QUEUE_RETRY);
+    }
+}
+
+/// Reads a frame whose sealed data is at most `max_length` bytes long
+pub(crate) fn read_frame<R: Read>(stream: &mut R, max_length: usize) -> Result<Frame> {
+    let package = Package::read_limited(stream, max_length)?;
+    let [footer] = read_array::<R, 1>(stream)?;
+
+    let digest = match footer {
+        footer::LAST => read_bytes(stream, digest_length(package.meta.digest))?,
+        _ => vec![],
+    };
+
+    Ok(Frame {
+        package,
+        footer,
+        digest,
+    })
+}
+
+/// Splits the data in packages of the channel, every package
+/// is handed out whole to be written
+pub(crate) fn write_message<R: Read, F: FnMut(&[u8]) -> Result<()>>(
+    packages: &mut Packages,
+    session_key: &SessionKey,
+    channel: u32,
+    reader: R,
+    length_hint: Option<usize>,
+    max_package_size: usize,
+    mut write: F,
+) -> Result<()> {
+    let mut writer =
+        PackagesSender::new(packages, Some(session_key), max_package_size, length_hint)?;
+    writer.set_channel(channel);
+
+    let mut chunks = Chunks::new(reader);
+
+    loop {
+        let (data, last) = chunks.next(writer.package_size())?;
+
+        // Channels are never acknowledged
+        let (buffer, _) = writer.frame(data, last)?;
+        write(&buffer)?;
+
+        writer.report();
+
+        if last {
+            break;
+        }
+    }
+
+    writer.finish();
+
+    Ok(())
+}
+
+/// Writes the data of the frames of a single message to the sink,
+/// returns the amount of bytes written
+pub(crate) fn read_message<W: Write, F: FnMut() -> Result<Frame>>(
+    session_key: &SessionKey,
+    max_package_size: usize,
+    mut sink: W,
+    mut next_frame: F,
+) -> Result<usize> {
+    // Channels are never acknowledged, adaptive batches accept the footers of every batch size
+    let mut receiver = PackagesReceiver::new(
+        [PackagesBatchSize::ADAPTIVE.to_value()],
+        Some(session_key.clone()),
+        max_package_size,
+    )?;
+    let mut written = 0;
+
+    loop {
+        let frame = next_frame()?;
+        let received = receiver.receive(frame.package, frame.footer)?;
+
+        sink.write_all(&received.data).map_err(MtpError::Io)?;
+        written += received.data.len();
+
+        if !received.more {
+            receiver.verify_digest(&frame.digest)?;
+            break;
+        }
+    }
+
+    sink.flush().map_err(MtpError::Io)?;
+
+    Ok(written)
+}
+
+/// Fails with [`MtpError::Incompatible`] when the other party can not multiplex
+pub(crate) fn check_multiplexed(capabilities: &Capabilities) -> Result<()> {
+    if !capabilities.supports(capability::MULTIPLEXED) {
+        return Err(MtpError::Incompatible(
+            "multiplexed channels were not negotiated",
+        ));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // A panicking channel can not leave the shared state half updated
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// One of the channels of a [`Multiplexer`], every message sent on a channel
+/// is received as a whole by the channel of the same identifier on the other side.
+///
+/// Dropping the channel discards the packages it has not read, packages
+/// received afterwards wait for the channel to be opened again.
+#[derive(Debug)]
+pub struct Channel {
+    id: u32,
+    shared: Arc<Shared>,
+    frames: Receiver<Frame>,
+}
+
+impl Channel {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Sends the data as a single message split in [`crate::package::PackageSize::MEDIUM`] packages
+    pub fn send(&mut self, data: Vec<u8>) -> Result<()> {
+        self.send_packages(Packages::new(data))
+    }
+
+    /// Same as [`Stream::send_packages`] over the channel,
+    /// packages of other channels are sent in between
+    pub fn send_packages(&mut self, mut packages: Packages) -> Result<()> {
+        let data = std::mem::take(&mut packages.data);
+
+        self.send_from_reader(packages, data.as_slice(), Some(data.len()))
+    }
+
+    /// Same as [`Stream::send_from_reader`] over the channel,
+    /// channels take no [`crate::package::options::WriteOptions`]
+    pub fn send_from_reader<R: Read>(
+        &mut self,
+        mut packages: Packages,
+        reader: R,
+        length_hint: Option<usize>,
+    ) -> Result<()> {
+        check_packages(&self.shared.capabilities, &packages)?;
+
+        write_message(
+            &mut packages,
+            &self.shared.session_key,
+            self.id,
+            reader,
+            length_hint,
+            self.shared.capabilities.max_package_size,
+            // Every package is written at once so packages of other channels only go in between
+            |buffer| Ok(lock(&self.shared.writer).write_all(buffer)?),
+        )
+    }
+
+    /// Waits for the next message of the channel
+    pub fn receive(&mut self) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        self.receive_into(&mut data)?;
+
+        Ok(data)
+    }
+
+    /// Same as [`Stream::receive_into`] for the next message of the channel,
+    /// channels take no [`crate::package::options::ReadOptions`]
+    pub fn receive_into<W: Write>(&mut self, sink: W) -> Result<usize> {
+        read_message(
+            &self.shared.session_key,
+            self.shared.capabilities.max_package_size,
+            sink,
+            || self.next_frame(),
+        )
+    }
+
+    fn next_frame(&self) -> Result<Frame> {
+        self.frames
+            .recv()
+            .map_err(|_| lock(&self.shared.channels).closed_error())
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        lock(&self.shared.channels).queues.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        capabilities::Capabilities,
+        error::MtpError,
+        multiplex::Multiplexer,
+        package::{packages::Packages, PackageSize},
+    };
+
+    fn multiplexed_connection() -> (Multiplexer, Multiplexer) {
+        let (server, client) = crate::tests::handshaken_pair(Capabilities::default());
+
+        (server.multiplex().unwrap(), client.multiplex().unwrap())
+    }
+
+    #[test]
+    fn channels_interleave_transfers() {
+        let (server, client) = multiplexed_connection();
+
+        let data = (0..200_000).map(|i| (i % 256) as u8).collect::<Vec<u8>>();
+
+        // Opened before anything is sent, messages of channels not open yet may be dropped
+        let mut bulk_receiver = server.channel(1).unwrap();
+        let mut messages_receiver = server.channel(2).unwrap();
+
+        let mut bulk = client.channel(1).unwrap();
+        let mut messages = client.channel(2).unwrap();
+
+        let sent = data.clone();
+        let bulk_sender = std::thread::spawn(move || {
+            let mut packages = Packages::new(vec![]);
+            packages.set_package_size(PackageSize::SMALL);
+
+            bulk.send_from_reader(packages, Cursor::new(sent), None)
+                .unwrap();
+        });
+        let messages_sender = std::thread::spawn(move || {
+            for i in 0..10u8 {
+                messages.send(vec![i; 10]).unwrap();
+            }
+        });
+
+        let bulk_receiver = std::thread::spawn(move || bulk_receiver.receive().unwrap());
+
+        for i in 0..10u8 {
+            assert_eq!(messages_receiver.receive().unwrap(), vec![i; 10]);
+        }
+
+        assert_eq!(bulk_receiver.join().unwrap(), data);
+
+        bulk_sender.join().unwrap();
+        messages_sender.join().unwrap();
+        client.shutdown().unwrap();
+    }
+
+    #[test]
+    fn channel_is_opened_once() {
+        let (server, client) = multiplexed_connection();
+
+        let channel = client.channel(7).unwrap();
+
+        assert!(matches!(client.channel(7), Err(MtpError::ChannelInUse(7))));
+
+        drop(channel);
+        assert!(client.channel(7).is_ok());
+
+        client.shutdown().unwrap();
+
+        let received = server.channel(7).and_then(|mut channel| channel.receive());
+        assert!(matches!(received, Err(MtpError::PeerAborted)));
+    }
+
+    #[test]
+    fn dropped_multiplexer_closes_connection() {
+        let (server, client) = multiplexed_connection();
+
+        let mut channel = server.channel(3).unwrap();
+        drop(client);
+
+        assert!(matches!(channel.receive(), Err(MtpError::PeerAborted)));
+    }
+
+    #[test]
+    fn unopened_channel_does_not_stall_others() {
+        let (server, client) = multiplexed_connection();
+
+        // Far more packages than a channel keeps until it is opened
+        let mut ignored = client.channel(9).unwrap();
+        let mut packages = Packages::new(vec![7; 10_000]);
+        packages.set_package_size(PackageSize::CUSTOM(100));
+        ignored.send_packages(packages).unwrap();
+
+        client.channel(1).unwrap().send(b"hello".to_vec()).unwrap();
+        assert_eq!(server.channel(1).unwrap().receive().unwrap(), b"hello");
+
+        // The dropped message is reported once and skipped as a whole
+        assert!(matches!(
+            server.channel(9),
+            Err(MtpError::MessagesDropped(9))
+        ));
+
+        ignored.send(b"again".to_vec()).unwrap();
+        assert_eq!(server.channel(9).unwrap().receive().unwrap(), b"again");
+    }
+
+    #[test]
+    fn too_many_unopened_channels_close_connection() {
+        let (server, client) = multiplexed_connection();
+
+        let mut opened = server.channel(0).unwrap();
+
+        for id in 1..=65 {
+            client.channel(id).unwrap().send(b"hello".to_vec()).unwrap();
+        }
+
+        assert!(matches!(
+            opened.receive(),
+            Err(MtpError::TooManyChannels(64))
+        ));
+    }
+}
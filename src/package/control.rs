@@ -0,0 +1,169 @@
+use std::{
+    io::Write,
+    sync::{Arc, Condvar, Mutex},
+};
+
+use crate::error::{MtpError, Result};
+
+/// Response byte written by the receiver every batch of packages
+pub mod response {
+    /// Keep sending
+    pub const CONTINUE: u8 = 0;
+    /// Stop the transfer, reported to both parties as [`crate::error::MtpError::Cancelled`]
+    pub const CANCEL: u8 = 1;
+    /// Wait for another response byte before sending more
+    pub const PAUSE: u8 = 2;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransferState {
+    #[default]
+    RUNNING,
+    PAUSED,
+    CANCELLED,
+}
+
+/// Handle controlling a transfer from the receiving side, clones control the same transfer
+/// so it can be handed to another thread while the receiver is blocked reading.
+///
+/// The receiver answers the sender once per batch of packages, so the sender
+/// stops at the end of the batch being sent when paused or cancelled.
+/// ```ignore
+/// let control = TransferControl::new();
+/// let handle = control.clone();
+///
+/// std::thread::spawn(move || {
+///     handle.pause();
+///     // ...
+///     handle.resume();
+/// });
+///
+/// stream.receive_into(file, ReadOptions::new().control(&control))?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TransferControl {
+    state: Arc<(Mutex<TransferState>, Condvar)>,
+}
+
+impl TransferControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn state(&self) -> TransferState {
+        *self.lock()
+    }
+
+    pub fn pause(&self) {
+        self.set_state(TransferState::PAUSED);
+    }
+
+    pub fn resume(&self) {
+        self.set_state(TransferState::RUNNING);
+    }
+
+    /// Cancelling is final, a cancelled transfer can not be resumed
+    pub fn cancel(&self) {
+        self.set_state(TransferState::CANCELLED);
+    }
+
+    fn set_state(&self, state: TransferState) {
+        let mut current = self.lock();
+
+        if *current != TransferState::CANCELLED {
+            *current = state;
+            self.state.1.notify_all();
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, TransferState> {
+        // The state is a plain value, a panic while holding the lock can not corrupt it
+        self.state
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn wait_while_paused(&self) -> TransferState {
+        let state = self.lock();
+
+        *self
+            .state
+            .1
+            .wait_while(state, |state| *state == TransferState::PAUSED)
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Writes the response of a batch, tells the sender to wait and
+    /// blocks while the transfer is paused
+    pub(crate) fn acknowledge<W: Write>(&self, stream: &mut W) -> Result<()> {
+        let mut state = self.state();
+
+        if state == TransferState::PAUSED {
+            stream.write_all(&[response::PAUSE])?;
+            state = self.wait_while_paused();
+        }
+
+        if state == TransferState::CANCELLED {
+            stream.write_all(&[response::CANCEL])?;
+            return Err(MtpError::Cancelled);
+        }
+
+        stream.write_all(&[response::CONTINUE])?;
+
+        Ok(())
+    }
+}
+
+/// Checks a response byte of the receiver, returns whether
+/// the sender has to wait for another one
+pub(crate) fn check_response(response: u8) -> Result<bool> {
+    match response {
+        response::CONTINUE => Ok(false),
+        response::CANCEL => Err(MtpError::Cancelled),
+        response::PAUSE => Ok(true),
+        _ => Err(MtpError::MalformedHeader("response byte must be 0, 1 or 2")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::MtpError,
+        package::control::{response, TransferControl, TransferState},
+    };
+
+    #[test]
+    fn paused_transfer_waits_for_resume() {
+        let control = TransferControl::new();
+        control.pause();
+
+        let resumer = control.clone();
+        let resume = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            resumer.resume();
+        });
+
+        let mut written = vec![];
+        control.acknowledge(&mut written).unwrap();
+        resume.join().unwrap();
+
+        assert_eq!(written, [response::PAUSE, response::CONTINUE]);
+        assert_eq!(control.state(), TransferState::RUNNING);
+    }
+
+    #[test]
+    fn cancelled_transfer_stays_cancelled() {
+        let control = TransferControl::new();
+        control.cancel();
+        control.resume();
+
+        let mut written = vec![];
+
+        assert!(matches!(
+            control.acknowledge(&mut written),
+            Err(MtpError::Cancelled)
+        ));
+        assert_eq!(written, [response::CANCEL]);
+    }
+}
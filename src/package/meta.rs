@@ -0,0 +1,249 @@
+use std::io::Read;
+
+use crate::{
+    bufferable::Bufferable,
+    error::{MtpError, Result},
+    utils::read_array,
+};
+
+/// Checksum appended to the package data (byte 8), see [`crate::package::Package`]
+pub mod checksum {
+    pub const NONE: u8 = 0;
+    /// 4 bytes CRC32C of the package data
+    pub const CRC32C: u8 = 1;
+}
+
+/// Digest sent after the last package of a transfer (byte 9),
+/// see [`crate::package::packages::Packages::set_transfer_digest`]
+pub mod digest {
+    pub const NONE: u8 = 0;
+    /// 32 bytes SHA-256 of the whole transfer data
+    pub const SHA256: u8 = 1;
+}
+
+/// Codec the package data is compressed with (byte 10), the codecs
+/// are only available with the cargo feature of the same name
+pub mod compression {
+    pub const NONE: u8 = 0;
+    /// The data is a zstd frame
+    pub const ZSTD: u8 = 1;
+    /// The data is an LZ4 frame
+    pub const LZ4: u8 = 2;
+}
+
+/// Bits of the flags byte (byte 15), the other bits are reserved and must be 0
+pub mod flags {
+    /// The data is sealed with the session key, see [`crate::package::Package::seal`]
+    pub const ENCRYPTED: u8 = 1 << 0;
+    /// The data length is a varint instead of 3 bytes, set for data longer
+    /// than [`crate::utils::MAX_HEADER_LENGTH`], see [`crate::codec::encode_varint`]
+    pub const EXTENDED_LENGTH: u8 = 1 << 1;
+
+    /// Every flag this version of the protocol knows about
+    pub const KNOWN: u8 = ENCRYPTED | EXTENDED_LENGTH;
+}
+
+/// What a package is part of (byte 14)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PackageType {
+    HANDSHAKE,
+    /// A package sent on its own
+    #[default]
+    PACKAGE,
+    /// One of the packages of a [`crate::package::packages::Packages`] transfer
+    PACKAGES,
+}
+
+impl PackageType {
+    pub fn to_value(&self) -> u8 {
+        match self {
+            Self::HANDSHAKE => 0,
+            Self::PACKAGE => 1,
+            Self::PACKAGES => 2,
+        }
+    }
+
+    pub fn from_value(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Self::HANDSHAKE),
+            1 => Ok(Self::PACKAGE),
+            2 => Ok(Self::PACKAGES),
+            _ => Err(MtpError::MalformedHeader("unknown package type")),
+        }
+    }
+}
+
+/// Header every package starts with, see [`PackageMeta::to_bytes`] for its layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PackageMeta {
+    /// Channel of a [`crate::multiplex::Multiplexer`] the package is sent on, 0 otherwise
+    pub channel: u32,
+    /// Position of the package in its transfer starting at 1, ensures order is maintained
+    pub item: u32,
+    /// [`checksum`] following the data
+    pub checksum: u8,
+    /// [`digest`] following the last package of a transfer
+    pub digest: u8,
+    /// [`compression`] of the data
+    pub compression: u8,
+    pub package_type: PackageType,
+    /// Bitset of [`flags`]
+    pub flags: u8,
+}
+
+impl PackageMeta {
+    pub fn new(item: u32, package_type: PackageType) -> Self {
+        Self {
+            item,
+            package_type,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & flags::ENCRYPTED != 0
+    }
+
+    pub fn has_extended_length(&self) -> bool {
+        self.flags & flags::EXTENDED_LENGTH != 0
+    }
+
+    /// Fails with [`MtpError::MalformedHeader`] when the package is not part of what is read
+    pub(crate) fn check_type(&self, package_type: PackageType) -> Result<()> {
+        if self.package_type != package_type {
+            return Err(MtpError::MalformedHeader("unexpected package type"));
+        }
+
+        Ok(())
+    }
+
+    /// Header model
+    /// - First 4 bytes (0 - 3); channel, big endian
+    /// - Next 4 bytes (4 - 7); item number, big endian
+    /// - Next byte (8); [`checksum`]
+    /// - Next byte (9); [`digest`]
+    /// - Next byte (10); [`compression`]
+    /// - Next 3 bytes (11 - 13); reserved, always 0
+    /// - Before last (14); [`PackageType`]
+    /// - Last byte (15); [`flags`]
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+
+        bytes[0..4].copy_from_slice(&self.channel.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.item.to_be_bytes());
+        bytes[8] = self.checksum;
+        bytes[9] = self.digest;
+        bytes[10] = self.compression;
+        bytes[14] = self.package_type.to_value();
+        bytes[15] = self.flags;
+
+        bytes
+    }
+
+    /// Parses a header written by [`PackageMeta::to_bytes`], fails with
+    /// [`MtpError::MalformedHeader`] on unknown types, flags or reserved bytes.
+    ///
+    /// The checksum, digest and compression are checked where they are used
+    pub fn from_bytes(bytes: [u8; 16]) -> Result<Self> {
+        if bytes[11..14] != [0; 3] {
+            return Err(MtpError::MalformedHeader(
+                "reserved package bytes must be 0",
+            ));
+        }
+
+        if bytes[15] & !flags::KNOWN != 0 {
+            return Err(MtpError::MalformedHeader("unknown package flags"));
+        }
+
+        Ok(Self {
+            channel: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            item: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            checksum: bytes[8],
+            digest: bytes[9],
+            compression: bytes[10],
+            package_type: PackageType::from_value(bytes[14])?,
+            flags: bytes[15],
+        })
+    }
+}
+
+impl Bufferable for PackageMeta {
+    fn to_buffer(self) -> Result<Vec<u8>> {
+        Ok(self.to_bytes().to_vec())
+    }
+
+    fn from_stream<R: Read>(stream: &mut R) -> Result<Self> {
+        Self::from_bytes(read_array(stream)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::MtpError,
+        package::meta::{checksum, compression, digest, flags, PackageMeta, PackageType},
+    };
+
+    #[test]
+    fn meta_round_trip() {
+        let meta = PackageMeta {
+            channel: 0xdead_beef,
+            item: 0x0102_0304,
+            checksum: checksum::CRC32C,
+            digest: digest::SHA256,
+            compression: compression::LZ4,
+            package_type: PackageType::PACKAGES,
+            flags: flags::ENCRYPTED,
+        };
+
+        let bytes = meta.to_bytes();
+
+        // Every byte of the 4 bytes fields is kept
+        assert_eq!(bytes[0..8], [0xde, 0xad, 0xbe, 0xef, 1, 2, 3, 4]);
+        assert_eq!(PackageMeta::from_bytes(bytes).unwrap(), meta);
+
+        for package_type in [
+            PackageType::HANDSHAKE,
+            PackageType::PACKAGE,
+            PackageType::PACKAGES,
+        ] {
+            let meta = PackageMeta::new(u32::MAX, package_type);
+
+            assert_eq!(PackageMeta::from_bytes(meta.to_bytes()).unwrap(), meta);
+            assert!(!meta.is_encrypted());
+        }
+    }
+
+    #[test]
+    fn package_types_are_distinct() {
+        let values = [
+            PackageType::HANDSHAKE,
+            PackageType::PACKAGE,
+            PackageType::PACKAGES,
+        ]
+        .map(|package_type| package_type.to_value());
+
+        assert_eq!(values, [0, 1, 2]);
+    }
+
+    #[test]
+    fn malformed_meta() {
+        let bytes = PackageMeta::new(1, PackageType::PACKAGE).to_bytes();
+
+        let mut unknown_type = bytes;
+        unknown_type[14] = 9;
+
+        let mut unknown_flag = bytes;
+        unknown_flag[15] = 1 << 7;
+
+        let mut reserved = bytes;
+        reserved[12] = 1;
+
+        for bytes in [unknown_type, unknown_flag, reserved] {
+            assert!(matches!(
+                PackageMeta::from_bytes(bytes),
+                Err(MtpError::MalformedHeader(_))
+            ));
+        }
+    }
+}
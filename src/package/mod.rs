@@ -1,20 +1,29 @@
-pub mod package_uuid;
+pub mod control;
+pub mod meta;
+pub mod options;
 pub mod packages;
+pub mod report;
+pub mod transfer;
 
 use std::io::Read;
 
-use uuid::Uuid;
-
 use crate::{
     bufferable::Bufferable,
-    utils::{macros::u8_bytes_to_usize, macros::usize_to_u8_bytes},
+    codec,
+    crypto::{SessionKey, SESSION_OVERHEAD},
+    error::{MtpError, Result},
+    utils::{read_bytes, MAX_EXTENDED_LENGTH, MAX_HEADER_LENGTH},
 };
 
+use self::meta::{checksum, compression, flags, PackageMeta};
+
 /// - tiny: `2^4 - 1`
 /// - small: `2^8 - 1`
 /// - medium: `2^12 - 1`
 /// - large: `2^16 - 1`
 /// - max: `2^24 - 1`
+/// - adaptive: from `2^8 - 1` up to `2^24 - 1`, picked while sending
+/// - custom: any size from 1 byte up to `2^32 - 1`
 #[derive(Debug, Clone, PartialEq, Default)]
 pub enum PackageSize {
     /// `15 bytes`
@@ -28,146 +37,345 @@ pub enum PackageSize {
     LARGE,
     // `16777215 bytes`
     MAX,
+    /// Starts at [`PackageSize::MEDIUM`] and grows or shrinks with the round trip and
+    /// throughput of every batch, see [`report::PackagesReport::package_size`]
+    ADAPTIVE,
+    /// That many bytes, packages above `2^24 - 1` bytes are sent with an extended
+    /// length header which both parties have to allow, see
+    /// [`crate::capabilities::Capabilities::max_package_size`]
+    CUSTOM(usize),
 }
 
 impl PackageSize {
+    /// Largest data of a package, the bound [`PackageSize::ADAPTIVE`] grows up to
     pub fn get_value(&self) -> usize {
         (match self {
             Self::TINY => usize::pow(2, 4),
             Self::SMALL => usize::pow(2, 8),
             Self::MEDIUM => usize::pow(2, 12),
             Self::LARGE => usize::pow(2, 16),
-            Self::MAX => usize::pow(2, 24),
+            Self::MAX | Self::ADAPTIVE => usize::pow(2, 24),
+            Self::CUSTOM(size) => return *size,
         } - 1)
     }
+
+    /// Ensures a custom size is between 1 and [`MAX_EXTENDED_LENGTH`] bytes,
+    /// fails with [`MtpError::InvalidPackageSize`] otherwise
+    pub fn check(&self) -> Result<()> {
+        match self {
+            Self::CUSTOM(size) if *size == 0 || *size > MAX_EXTENDED_LENGTH => {
+                Err(MtpError::InvalidPackageSize(*size))
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 /// pieces of data which can contain upto 65535 (0.5MB) bytes of data
 ///
 #[derive(Debug, Clone, PartialEq)]
 pub struct Package {
-    /// UUID Explains
-    /// - First 4 bytes (0 - 3); Item identifier
-    /// - Next 4 bytes (4 - 7); item number (Ensures order will be maintain once received)
-    /// - Next 6 bytes (8 - 13); Free bytes
-    /// - Before last (14); type marker; `0 -> handshake, 1 -> Package`
-    /// - Last byte (15); `encrypted -> 1 / not encrypted -> 0` mark
-    pub meta_uuid: Uuid,
+    /// Header of the package, see [`PackageMeta::to_bytes`] for its layout
+    pub meta: PackageMeta,
     pub data: Vec<u8>,
 }
 
 impl Package {
-    pub fn new(data: Vec<u8>, meta_uuid: Uuid) -> Self {
-        Self { meta_uuid, data }
+    pub fn new(data: Vec<u8>, meta: PackageMeta) -> Self {
+        Self { meta, data }
     }
 
-    fn read_meta_uuid(tcp_stream: &mut std::net::TcpStream) -> Uuid {
-        let mut uuid_bytes = [0; 16];
-
-        tcp_stream
-            .read(&mut uuid_bytes)
-            .expect("Expected bytes for package UUID");
+    /// Encrypts the data with the session key and marks the package as encrypted,
+    /// the meta header is authenticated along with the data.
+    pub fn seal(self, session_key: &SessionKey) -> Result<Self> {
+        let meta = PackageMeta {
+            flags: self.meta.flags | flags::ENCRYPTED,
+            ..self.meta
+        };
+        let data = session_key.seal(&meta.to_bytes(), &self.data)?;
 
-        Uuid::from_bytes(uuid_bytes)
+        Ok(Self { meta, data })
     }
 
-    fn read_missing(
-        tcp_stream: &mut std::net::TcpStream,
-        missing_length: usize,
-        total_size: usize,
-    ) -> Vec<u8> {
-        let mut data = vec![0; missing_length];
+    /// Decrypts a package sealed with [`Package::seal`], refuses with
+    /// [`MtpError::NotEncrypted`] packages not marked as encrypted
+    pub fn open(self, session_key: &SessionKey) -> Result<Self> {
+        if !self.meta.is_encrypted() {
+            return Err(MtpError::NotEncrypted);
+        }
 
-        let length_read = tcp_stream
-            .read(&mut data)
-            .expect("Expected to read more missing data");
+        let data = session_key.open(&self.meta.to_bytes(), &self.data)?;
 
-        if missing_length - length_read == 0 {
-            data
-        } else {
-            if missing_length - length_read > 0 {
-                panic!(
-                    "Overread data {} - {}",
-                    missing_length - length_read,
-                    length_read
-                );
-            }
+        Ok(Self {
+            meta: PackageMeta {
+                flags: self.meta.flags & !flags::ENCRYPTED,
+                ..self.meta
+            },
+            data,
+        })
+    }
 
-            Self::read_missing(tcp_stream, length_read - missing_length, total_size)
+    /// Compresses the data with the codec and marks the package as compressed,
+    /// data which does not get smaller is kept as it is and marked as uncompressed
+    pub fn compress(self, compression: u8) -> Result<Self> {
+        let compressed = compress_data(compression, &self.data)?;
+
+        if compressed.len() >= self.data.len() {
+            return Ok(Self {
+                meta: PackageMeta {
+                    compression: compression::NONE,
+                    ..self.meta
+                },
+                data: self.data,
+            });
         }
+
+        Ok(Self {
+            meta: PackageMeta {
+                compression,
+                ..self.meta
+            },
+            data: compressed,
+        })
     }
 
-    /// maximum data length is `2^24 - 1` because the
-    /// data_length header is only 3 bytes long
-    ///  
-    /// How is calculated:
-    /// `255 * 256` + `255 * 256 ^ 2` + `255` = `16777215` or `2^24 - 1`
-    fn read_data(tcp_stream: &mut std::net::TcpStream) -> Vec<u8> {
-        let mut data_length_bytes = [0; 3];
-        tcp_stream
-            .read(&mut data_length_bytes)
-            .expect("Expected package data length");
+    /// Decompresses a package compressed with [`Package::compress`], fails with
+    /// [`MtpError::Incompatible`] when the codec is not enabled in this build
+    pub fn decompress(self) -> Result<Self> {
+        self.decompress_limited(MAX_EXTENDED_LENGTH)
+    }
 
-        let data_length = u8_bytes_to_usize!(data_length_bytes);
+    /// Same as [`Package::decompress`] but data decompressing to more than
+    /// `max_length` bytes fails with [`MtpError::OversizedLength`]
+    pub(crate) fn decompress_limited(self, max_length: usize) -> Result<Self> {
+        let data = decompress_data(self.meta.compression, self.data, max_length)?;
+
+        Ok(Self {
+            meta: PackageMeta {
+                compression: compression::NONE,
+                ..self.meta
+            },
+            data,
+        })
+    }
 
-        let mut data_bytes = vec![0; data_length];
-        let data_read_length = tcp_stream
-            .read(&mut data_bytes)
-            .expect("Expected data bytes");
+    /// Marks the package to be sent with a checksum of its data, a package
+    /// whose data does not match its checksum fails with [`MtpError::ChecksumMismatch`]
+    pub fn with_checksum(self, checksum: u8) -> Self {
+        Self {
+            meta: PackageMeta {
+                checksum,
+                ..self.meta
+            },
+            data: self.data,
+        }
+    }
 
-        if data_read_length == data_length {
+    /// Length of the buffer written by [`Bufferable::to_buffer`]
+    pub(crate) fn wire_length(&self) -> usize {
+        let length_header = if self.data.len() > MAX_HEADER_LENGTH {
+            codec::encode_varint(self.data.len()).len()
         } else {
-            // println!("Missing data {}", data_length - data_read_length);
-            // println!("--- ERROR - NO ENOUGH DATA READ : {}", data_read_length);
+            3
+        };
+        let checksum = checksum_length(self.meta.checksum).unwrap_or(0);
 
-            data_bytes.truncate(data_read_length);
+        16 + length_header + self.data.len() + checksum
+    }
 
-            data_bytes.append(&mut Self::read_missing(
-                tcp_stream,
-                data_length - data_read_length,
-                data_length,
-            ));
+    /// Same as [`Bufferable::from_stream`] but a package whose data is longer than
+    /// `max_length` fails with [`MtpError::OversizedLength`] before its data is read
+    pub(crate) fn read_limited<R: Read>(stream: &mut R, max_length: usize) -> Result<Self> {
+        let mut meta = PackageMeta::from_stream(stream)?;
+        let data = Self::read_data(stream, &meta, max_length)?;
+
+        // The length flag only describes the frame, it is set again when sent
+        meta.flags &= !flags::EXTENDED_LENGTH;
+
+        let checksum = read_bytes(stream, checksum_length(meta.checksum)?)?;
+
+        if checksum != checksum_of(meta.checksum, &data)? {
+            return Err(MtpError::ChecksumMismatch {
+                item: meta.item as usize,
+            });
         }
 
-        data_bytes
+        Ok(Self { data, meta })
+    }
+
+    /// The data length header is 3 bytes long, so up to `2^24 - 1`, unless the meta
+    /// header has [`flags::EXTENDED_LENGTH`] where it is a varint up to [`MAX_EXTENDED_LENGTH`]
+    fn read_data<R: Read>(
+        stream: &mut R,
+        meta: &PackageMeta,
+        max_length: usize,
+    ) -> Result<Vec<u8>> {
+        let length = if meta.has_extended_length() {
+            codec::read_varint(stream)?
+        } else {
+            codec::read::<R, 3>(stream)?
+        };
+
+        read_bytes(stream, check_length(length, max_length)?)
     }
 }
 
 impl Bufferable for Package {
     /// Buffer model
-    /// - First 16 bytes (0, 15) META UUID
-    /// - Next 3 bytes (16, 18) Data length
-    /// - Rest data bytes
-    fn to_buffer(mut self) -> Vec<u8> {
+    /// - First 16 bytes (0, 15) [`PackageMeta`]
+    /// - Next 3 bytes (16, 18) Data length, or a varint when the data is
+    ///   longer than `2^24 - 1`, see [`flags::EXTENDED_LENGTH`]
+    /// - Next data bytes
+    /// - Rest checksum bytes, see [`meta::checksum`]
+    fn to_buffer(mut self) -> Result<Vec<u8>> {
         let mut buffer = vec![];
+        let mut checksum = checksum_of(self.meta.checksum, &self.data)?;
+
+        if self.data.len() > MAX_HEADER_LENGTH {
+            self.meta.flags |= flags::EXTENDED_LENGTH;
+            buffer.extend_from_slice(&self.meta.to_bytes());
+            buffer.append(&mut codec::encode_varint(check_extended_length(
+                self.data.len(),
+            )?));
+        } else {
+            self.meta.flags &= !flags::EXTENDED_LENGTH;
+            buffer.extend_from_slice(&self.meta.to_bytes());
+            buffer.extend_from_slice(&codec::encode::<3>(self.data.len())?);
+        }
 
-        buffer.append(&mut self.meta_uuid.as_bytes().to_vec());
+        buffer.append(&mut self.data);
 
-        buffer.append(&mut usize_to_u8_bytes!((self.data.len()); 3).to_vec());
+        buffer.append(&mut checksum);
 
-        buffer.append(&mut self.data);
+        Ok(buffer)
+    }
 
-        buffer
+    fn from_stream<R: Read>(stream: &mut R) -> Result<Self> {
+        Self::read_limited(stream, MAX_EXTENDED_LENGTH)
     }
+}
 
-    fn from_stream(tcp_stream: &mut std::net::TcpStream) -> Self {
-        let meta_uuid = Self::read_meta_uuid(tcp_stream);
-        let data = Self::read_data(tcp_stream);
+/// Ensures a length fits in an extended length header
+pub(crate) fn check_extended_length(length: usize) -> Result<usize> {
+    check_length(length, MAX_EXTENDED_LENGTH)
+}
 
-        Self { data, meta_uuid }
+/// Ensures a length received from the other party is not above the maximum
+pub(crate) fn check_length(length: usize, max: usize) -> Result<usize> {
+    if length > max {
+        return Err(MtpError::OversizedLength { length, max });
+    }
+
+    Ok(length)
+}
+
+/// Longest data of a sealed package carrying up to `max_package_size` bytes
+pub(crate) fn max_sealed_length(max_package_size: usize) -> usize {
+    max_package_size
+        .saturating_add(SESSION_OVERHEAD)
+        .min(MAX_EXTENDED_LENGTH)
+}
+
+fn compress_data(compression: u8, data: &[u8]) -> Result<Vec<u8>> {
+    match compression {
+        compression::NONE => Ok(data.to_vec()),
+        #[cfg(feature = "zstd")]
+        compression::ZSTD => zstd::bulk::compress(data, 0).map_err(MtpError::Io),
+        #[cfg(feature = "lz4")]
+        compression::LZ4 => {
+            use std::io::Write;
+
+            let mut encoder = lz4_flex::frame::FrameEncoder::new(vec![]);
+            encoder.write_all(data).map_err(MtpError::Io)?;
+
+            encoder.finish().map_err(|err| MtpError::Io(err.into()))
+        }
+        _ => Err(unsupported_compression(compression)),
+    }
+}
+
+/// Decompressed data is refused once longer than `max_length`, the
+/// largest data a package may carry before being compressed
+fn decompress_data(compression: u8, data: Vec<u8>, max_length: usize) -> Result<Vec<u8>> {
+    match compression {
+        compression::NONE => {
+            check_length(data.len(), max_length)?;
+            Ok(data)
+        }
+        #[cfg(feature = "zstd")]
+        compression::ZSTD => {
+            let decoder = zstd::stream::read::Decoder::with_buffer(data.as_slice())
+                .map_err(|_| MtpError::MalformedHeader("package data is not valid zstd"))?;
+
+            read_decompressed(decoder, max_length, "package data is not valid zstd")
+        }
+        #[cfg(feature = "lz4")]
+        compression::LZ4 => {
+            let decoder = lz4_flex::frame::FrameDecoder::new(data.as_slice());
+
+            read_decompressed(decoder, max_length, "package data is not valid lz4")
+        }
+        _ => Err(unsupported_compression(compression)),
+    }
+}
+
+/// Streams the data out of the decoder up to a byte past `max_length`, whatever
+/// length the compressed data claims nothing more is ever allocated
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+fn read_decompressed<R: Read>(
+    decoder: R,
+    max_length: usize,
+    invalid: &'static str,
+) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    decoder
+        .take((max_length as u64).saturating_add(1))
+        .read_to_end(&mut data)
+        .map_err(|_| MtpError::MalformedHeader(invalid))?;
+
+    check_length(data.len(), max_length)?;
+
+    Ok(data)
+}
+
+fn unsupported_compression(compression: u8) -> MtpError {
+    match compression {
+        compression::ZSTD => MtpError::Incompatible("zstd compression is not enabled"),
+        compression::LZ4 => MtpError::Incompatible("lz4 compression is not enabled"),
+        _ => MtpError::MalformedHeader("unknown package compression"),
+    }
+}
+
+/// Length of the checksum following the data
+pub(crate) fn checksum_length(checksum: u8) -> Result<usize> {
+    match checksum {
+        checksum::NONE => Ok(0),
+        checksum::CRC32C => Ok(4),
+        _ => Err(MtpError::MalformedHeader("unknown package checksum")),
+    }
+}
+
+fn checksum_of(checksum: u8, data: &[u8]) -> Result<Vec<u8>> {
+    match checksum {
+        checksum::NONE => Ok(vec![]),
+        checksum::CRC32C => Ok(crc32c::crc32c(data).to_be_bytes().to_vec()),
+        _ => Err(MtpError::MalformedHeader("unknown package checksum")),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Write;
+    use std::io::{Cursor, Write};
 
     use crate::{
         bufferable::Bufferable,
+        codec::encode_varint,
+        error::MtpError,
         package::{
-            package_uuid::{encryption, new_uuid, typemarkers},
+            meta::{checksum, flags, PackageMeta, PackageType},
             Package,
         },
+        tests::Trickle,
     };
 
     #[test]
@@ -177,18 +385,164 @@ mod tests {
 
         // Data setup by the Client to send to server
         let data = "Hello I'm the client, and this is a friendly package";
-        let meta_uuid = new_uuid(1, vec![0], typemarkers::PACKAGE, encryption::UNENCRYPTED);
-        let package = Package::new(data.as_bytes().to_vec(), meta_uuid.clone());
+        let meta = PackageMeta::new(1, PackageType::PACKAGE);
+        let package = Package::new(data.as_bytes().to_vec(), meta);
 
         client_stream
             .tcp_stream
-            .write(&package.clone().to_buffer())
+            .write_all(&package.clone().to_buffer().unwrap())
             .expect("expected to write data to the server");
 
         // Read in server
-        let client_package = Package::from_stream(&mut server_stream.tcp_stream);
+        let client_package = Package::from_stream(&mut server_stream.tcp_stream).unwrap();
 
-        assert_eq!(package.meta_uuid, client_package.meta_uuid);
+        assert_eq!(package.meta, client_package.meta);
         assert_eq!(package.data, client_package.data);
     }
+
+    #[test]
+    fn extended_length_package() {
+        let meta = PackageMeta::new(1, PackageType::PACKAGE);
+
+        // Small packages keep the 3 bytes length header
+        let buffer = Package::new(vec![1; 100], meta).to_buffer().unwrap();
+        assert_eq!(buffer[15] & flags::EXTENDED_LENGTH, 0);
+        assert_eq!(buffer.len(), 16 + 3 + 100);
+
+        let package = Package::new(vec![2; usize::pow(2, 24)], meta);
+        let buffer = package.clone().to_buffer().unwrap();
+
+        // 2^24 takes a 4 bytes varint
+        assert_eq!(buffer[15] & flags::EXTENDED_LENGTH, flags::EXTENDED_LENGTH);
+        assert_eq!(buffer.len(), 16 + 4 + usize::pow(2, 24));
+        assert_eq!(Package::from_stream(&mut Cursor::new(buffer)).unwrap(), package);
+    }
+
+    #[test]
+    fn oversized_extended_length() {
+        let meta = PackageMeta {
+            flags: flags::EXTENDED_LENGTH,
+            ..PackageMeta::new(1, PackageType::PACKAGE)
+        };
+
+        let mut buffer = meta.to_bytes().to_vec();
+        buffer.append(&mut encode_varint(usize::pow(2, 32)));
+
+        assert!(matches!(
+            Package::from_stream(&mut Cursor::new(buffer)),
+            Err(MtpError::OversizedLength { .. })
+        ));
+    }
+
+    #[test]
+    fn package_over_in_memory_buffer() {
+        let meta = PackageMeta::new(7, PackageType::PACKAGE);
+        let package = Package::new(b"framed without a socket".to_vec(), meta);
+
+        let mut buffer = Cursor::new(package.clone().to_buffer().unwrap());
+
+        assert_eq!(Package::from_stream(&mut buffer).unwrap(), package);
+    }
+
+    #[test]
+    fn package_over_short_reads() {
+        let meta = PackageMeta::new(3, PackageType::PACKAGE);
+        let data = (0..5000).map(|i| (i % 256) as u8).collect::<Vec<u8>>();
+        let package = Package::new(data, meta);
+
+        let mut stream = Trickle(Cursor::new(package.clone().to_buffer().unwrap()));
+
+        assert_eq!(Package::from_stream(&mut stream).unwrap(), package);
+    }
+
+    #[test]
+    fn truncated_package() {
+        let meta = PackageMeta::new(3, PackageType::PACKAGE);
+        let package = Package::new(vec![1; 100], meta);
+
+        let mut buffer = package.to_buffer().unwrap();
+        buffer.truncate(60);
+
+        assert!(matches!(
+            Package::from_stream(&mut Cursor::new(buffer)),
+            Err(MtpError::PeerAborted)
+        ));
+    }
+
+    #[test]
+    fn package_with_checksum() {
+        let meta = PackageMeta::new(9, PackageType::PACKAGE);
+        let package = Package::new(vec![7; 100], meta).with_checksum(checksum::CRC32C);
+
+        let buffer = package.clone().to_buffer().unwrap();
+        assert_eq!(buffer.len(), 16 + 3 + 100 + 4);
+        assert_eq!(Package::from_stream(&mut Cursor::new(buffer.clone())).unwrap(), package);
+
+        // A flipped bit in the data is reported with the item number of the package
+        let mut corrupted = buffer;
+        corrupted[16 + 3 + 50] ^= 1;
+
+        assert!(matches!(
+            Package::from_stream(&mut Cursor::new(corrupted)),
+            Err(MtpError::ChecksumMismatch { item: 9 })
+        ));
+    }
+
+    #[test]
+    #[cfg(any(feature = "zstd", feature = "lz4"))]
+    fn package_compression() {
+        use crate::package::meta::compression;
+
+        let meta = PackageMeta::new(1, PackageType::PACKAGE);
+        let package = Package::new(b"log line\n".repeat(500), meta);
+
+        let codecs = [
+            #[cfg(feature = "zstd")]
+            compression::ZSTD,
+            #[cfg(feature = "lz4")]
+            compression::LZ4,
+        ];
+
+        for codec in codecs {
+            let compressed = package.clone().compress(codec).unwrap();
+
+            assert_eq!(compressed.meta.compression, codec);
+            assert!(compressed.data.len() < package.data.len());
+            assert_eq!(compressed.clone().decompress().unwrap(), package);
+
+            // Decompressed data is bounded by the largest package allowed
+            assert!(matches!(
+                compressed.decompress_limited(package.data.len() - 1),
+                Err(MtpError::OversizedLength { .. })
+            ));
+
+            // A few bytes claiming megabytes are refused without decompressing them whole
+            let bomb = Package::new(vec![0; 1 << 24], meta).compress(codec).unwrap();
+            assert!(matches!(
+                bomb.decompress_limited(4096),
+                Err(MtpError::OversizedLength { length: 4097, max: 4096 })
+            ));
+
+            // Data which does not shrink is kept as it is
+            let random = Package::new((0..64).map(|_| rand::random()).collect(), meta);
+            let kept = random.clone().compress(codec).unwrap();
+
+            assert_eq!(kept.meta.compression, compression::NONE);
+            assert_eq!(kept, random);
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "zstd"))]
+    fn disabled_compression() {
+        use crate::package::meta::compression;
+
+        let meta = PackageMeta {
+            compression: compression::ZSTD,
+            ..PackageMeta::new(1, PackageType::PACKAGE)
+        };
+        let package = Package::new(vec![0; 100], meta);
+
+        assert!(matches!(package.decompress(), Err(MtpError::Incompatible(_))));
+    }
 }
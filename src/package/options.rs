@@ -0,0 +1,155 @@
+use uuid::Uuid;
+
+use crate::{crypto::SessionKey, utils::MAX_EXTENDED_LENGTH};
+
+use super::{control::TransferControl, report::PackagesReporter, transfer::TransferLog};
+
+/// Configures a transfer sent with [`super::packages::Packages::write_from_reader`]
+/// ```ignore
+/// let file = File::open("music.flac")?;
+/// let length = file.metadata()?.len() as usize;
+///
+/// let options = WriteOptions::new()
+///     .session_key(&session_key)
+///     .resumable(transfer_id);
+///
+/// Packages::new(vec![]).write_from_reader(&mut stream, file, Some(length), options)?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    pub(crate) session_key: Option<SessionKey>,
+    pub(crate) transfer_id: Option<Uuid>,
+    /// Packages are never split above it, the size negotiated by shaken streams
+    pub(crate) max_package_size: usize,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            session_key: None,
+            transfer_id: None,
+            max_package_size: MAX_EXTENDED_LENGTH,
+        }
+    }
+}
+
+impl WriteOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seals every package with the session key, shaken streams
+    /// always use the key agreed during the handshake
+    pub fn session_key(mut self, session_key: &SessionKey) -> Self {
+        self.session_key = Some(session_key.clone());
+        self
+    }
+
+    /// # Resumable transfers
+    /// The transfer is identified by the transfer id, when the receiver already
+    /// persisted part of that transfer the packages it holds are skipped and the
+    /// transfer restarts from the next one.
+    /// - A -> B `[16 bytes transfer id]`
+    /// - B -> A `[4 bytes last contiguous item number][8 bytes persisted bytes]`
+    /// - Then the packages streaming protocol starting at the next item number
+    ///
+    /// The persisted bytes are read and discarded from the reader and the package
+    /// size must stay the same when resuming a transfer.
+    /// The other side reads it with [`ReadOptions::resumable`].
+    pub fn resumable(mut self, transfer_id: Uuid) -> Self {
+        self.transfer_id = Some(transfer_id);
+        self
+    }
+
+    pub(crate) fn max_package_size(mut self, max_package_size: usize) -> Self {
+        self.max_package_size = max_package_size;
+        self
+    }
+}
+
+/// Configures a transfer received with [`super::packages::Packages::read_into`]
+/// ```ignore
+/// let control = TransferControl::new();
+/// let reporter = PackagesReporter::new(|report| println!("{} bytes", report.bytes_sent));
+///
+/// let options = ReadOptions::new().control(&control).reporter(reporter);
+///
+/// Packages::read_into(&mut stream, File::create("music.flac")?, options)?;
+/// ```
+#[derive(Debug)]
+pub struct ReadOptions<'a> {
+    pub(crate) session_key: Option<SessionKey>,
+    pub(crate) control: Option<&'a TransferControl>,
+    pub(crate) reporter: Option<PackagesReporter>,
+    pub(crate) log: Option<&'a mut TransferLog>,
+    /// Packages whose data is longer are refused, the size negotiated by shaken streams
+    pub(crate) max_package_size: usize,
+}
+
+impl Default for ReadOptions<'_> {
+    fn default() -> Self {
+        Self {
+            session_key: None,
+            control: None,
+            reporter: None,
+            log: None,
+            max_package_size: MAX_EXTENDED_LENGTH,
+        }
+    }
+}
+
+impl<'a> ReadOptions<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens packages sealed with the session key, shaken streams
+    /// always use the key agreed during the handshake
+    pub fn session_key(mut self, session_key: &SessionKey) -> Self {
+        self.session_key = Some(session_key.clone());
+        self
+    }
+
+    /// The transfer can be paused, resumed or cancelled through the control
+    /// while it is received
+    /// ```ignore
+    /// let control = TransferControl::new();
+    /// let cancel = control.clone();
+    /// ctrlc::set_handler(move || cancel.cancel())?;
+    ///
+    /// match Packages::read_into(&mut stream, file, ReadOptions::new().control(&control)) {
+    ///     Err(MtpError::Cancelled) => println!("transfer cancelled"),
+    ///     result => result?,
+    /// }
+    /// ```
+    pub fn control(mut self, control: &'a TransferControl) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// The progress of the transfer is reported as packages arrive, see [`PackagesReporter`]
+    pub fn reporter(mut self, reporter: PackagesReporter) -> Self {
+        self.reporter = Some(reporter);
+        self
+    }
+
+    /// Reads a transfer sent with [`WriteOptions::resumable`], the progress of the
+    /// transfer is recorded in the log as packages are written to the sink and
+    /// flushed, the transfer resumes from it when the sender retries
+    /// ```ignore
+    /// let mut log = TransferLog::load("transfers")?;
+    /// let file = OpenOptions::new().append(true).create(true).open("music.flac")?;
+    ///
+    /// let result = Packages::read_into(&mut stream, file, ReadOptions::new().resumable(&mut log));
+    /// log.save("transfers")?;
+    /// ```
+    pub fn resumable(mut self, log: &'a mut TransferLog) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    pub(crate) fn max_package_size(mut self, max_package_size: usize) -> Self {
+        self.max_package_size = max_package_size;
+        self
+    }
+}
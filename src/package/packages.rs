@@ -2,17 +2,40 @@
 
 use std::{
     io::{Read, Write},
-    net::TcpStream,
+    time::{Duration, Instant},
 };
 
+use openssl::sha::Sha256;
+use uuid::Uuid;
+
 use crate::{
     bufferable::Bufferable,
-    package::{Package, PackageSize},
+    crypto::{SessionKey, SESSION_OVERHEAD},
+    error::{MtpError, Result},
+    package::{max_sealed_length, Package, PackageSize},
+    utils::{read_array, read_bytes, MAX_EXTENDED_LENGTH, MAX_HEADER_LENGTH},
+};
+
+use super::{
+    control::{check_response, response, TransferControl},
+    meta::{checksum, compression, digest, PackageMeta, PackageType},
+    options::{ReadOptions, WriteOptions},
+    report::{PackageReportSpeed, PackagesReport, PackagesReporter, Reports},
+    transfer::TransferProgress,
 };
 
-use super::package_uuid::{encryption, new_uuid, typemarkers};
+/// Round trip of a batch above which adaptive sizes shrink,
+/// keeps pauses and cancels taking effect quickly
+const TARGET_ROUND_TRIP: Duration = Duration::from_millis(100);
 
-type PackageReportCallback = fn(PackagesReport);
+/// Footer byte following every package
+pub(crate) mod footer {
+    pub const LAST: u8 = 0;
+    pub const MORE: u8 = 1;
+    /// More packages follow and the batch ends here, only sent with
+    /// [`super::PackagesBatchSize::ADAPTIVE`] where batches vary in length
+    pub const END_OF_BATCH: u8 = 2;
+}
 
 /// # Packages procedure
 /// Use to allow other structures to turn
@@ -21,57 +44,27 @@ pub trait Packable {
     fn pack() -> Packages;
 }
 
-#[derive(Debug)]
-pub struct PackagesReport {
-    pub sent: usize,
-    pub total: usize,
-    pub bytes_sent: usize,
-    pub total_bytes: usize,
-}
-
-/// Slows down reports by skipping reporting certain ones
-/// - fastest: No skips
-/// - fast: Skips every 1%
-/// - steady: Skips every 5%
-/// - slow: Skips every 10%
-/// - slowest: FAST: Skips every 15%
-///
-/// Note: If the total packages is too small it will not be noticable  
-#[derive(Debug, Default)]
-pub enum PackageReportSpeed {
-    FASTEST,
-    FAST,
-    #[default]
-    STEADY,
-    SLOW,
-    SLOWEST,
-}
-
-impl PackageReportSpeed {
-    pub fn apply_multiplier(&self, value: usize) -> usize {
-        match self {
-            Self::FASTEST => 1,
-            Self::FAST => (value as f32 * 0.01).ceil() as usize,
-            Self::STEADY => (value as f32 * 0.05).ceil() as usize,
-            Self::SLOW => (value as f32 * 0.1).ceil() as usize,
-            Self::SLOWEST => (value as f32 * 0.15).ceil() as usize,
-        }
-    }
-}
-
 /// # Protocol procedure
 /// Packages are send one after another.
 /// After each package one byte is reserve to let
 /// the other side know whether to expect more or not
 /// - 1: more incoming packages
 /// - 0: no more incoming packages
+/// - 2: more incoming packages, the batch ends here (adaptive batches only)
+///
+/// After every batch the receiver answers with one byte, see [`response`]
+/// - 0: continue
+/// - 1: cancel the transfer
+/// - 2: pause, another response byte follows once resumed
 #[derive(Debug)]
 pub struct Packages {
     pub data: Vec<u8>,
-    reports_callback: Option<PackageReportCallback>,
-    reports_speed: Option<PackageReportSpeed>,
+    reporter: PackagesReporter,
     packages_size: PackageSize,
     batch_size: PackagesBatchSize,
+    checksum: u8,
+    digest: u8,
+    compression: u8,
 }
 
 /// How many packages are sent at once before the
@@ -81,7 +74,8 @@ pub struct Packages {
 /// - medium: 16 packages
 /// - large: 64 packages
 /// - max: 255 packages
-
+/// - adaptive: from 1 up to 255 packages, picked while sending
+/// - custom: any number from 1 up to 255 packages
 #[derive(Debug, Clone, Copy, Default)]
 pub enum PackagesBatchSize {
     TINY,
@@ -90,6 +84,11 @@ pub enum PackagesBatchSize {
     MEDIUM,
     LARGE,
     MAX,
+    /// Starts at [`PackagesBatchSize::SMALL`] and grows or shrinks with the round trip
+    /// and throughput of every batch, see [`PackagesReport::batch_size`]
+    ADAPTIVE,
+    /// That many packages, 0 is refused as it marks adaptive batches on the wire
+    CUSTOM(u8),
 }
 
 impl PackagesBatchSize {
@@ -100,17 +99,28 @@ impl PackagesBatchSize {
             Self::MEDIUM => u8::pow(2, 4),
             Self::LARGE => u8::pow(2, 6),
             Self::MAX => (u16::pow(2, 8) - 1) as u8,
+            Self::ADAPTIVE => 0,
+            Self::CUSTOM(size) => *size,
         }
     }
 
-    pub fn from_value(value: u8) -> Result<Self, ()> {
+    pub fn from_value(value: u8) -> Result<Self> {
         match value {
             1 => Ok(Self::TINY),
             4 => Ok(Self::SMALL),
             16 => Ok(Self::MEDIUM),
             64 => Ok(Self::LARGE),
             255 => Ok(Self::MAX),
-            _ => Err(()),
+            0 => Ok(Self::ADAPTIVE),
+            _ => Ok(Self::CUSTOM(value)),
+        }
+    }
+
+    /// Ensures a custom size is not 0, fails with [`MtpError::InvalidBatchSize`] otherwise
+    pub fn check(&self) -> Result<()> {
+        match self {
+            Self::CUSTOM(0) => Err(MtpError::InvalidBatchSize(0)),
+            _ => Ok(()),
         }
     }
 }
@@ -119,10 +129,12 @@ impl Packages {
     pub fn new(data: Vec<u8>) -> Self {
         Self {
             data,
-            reports_callback: None,
-            reports_speed: Some(PackageReportSpeed::default()),
+            reporter: PackagesReporter::default(),
             packages_size: PackageSize::default(),
             batch_size: PackagesBatchSize::default(),
+            checksum: checksum::NONE,
+            digest: digest::NONE,
+            compression: compression::NONE,
         }
     }
 
@@ -134,12 +146,47 @@ impl Packages {
         self.packages_size = package_size;
     }
 
-    pub fn listen_reports(&mut self, f: PackageReportCallback) {
-        self.reports_callback = Some(f)
+    pub fn package_size(&self) -> &PackageSize {
+        &self.packages_size
+    }
+
+    pub fn listen_reports<F: FnMut(PackagesReport) + Send + 'static>(&mut self, f: F) {
+        self.reporter.listen(f)
     }
 
     pub fn set_report_speed(&mut self, f: PackageReportSpeed) {
-        self.reports_speed = Some(f)
+        self.reporter.set_report_speed(f)
+    }
+
+    /// Sends at most one report per interval, see [`PackagesReporter::set_report_interval`]
+    pub fn set_report_interval(&mut self, interval: Duration) {
+        self.reporter.set_report_interval(interval)
+    }
+
+    /// Sends every package with a [`checksum`] of its data, the receiver fails
+    /// with [`MtpError::ChecksumMismatch`] naming the first corrupted package
+    pub fn set_checksum(&mut self, checksum: u8) {
+        self.checksum = checksum;
+    }
+
+    /// Sends a [`digest`] of the whole data after the last package, the receiver
+    /// fails with [`MtpError::DigestMismatch`] when the data it got does not match.
+    ///
+    /// The digest is not verified by receivers resuming a transfer as they do
+    /// not hold the data persisted before
+    pub fn set_transfer_digest(&mut self, digest: u8) {
+        self.digest = digest;
+    }
+
+    /// Compresses every package with the [`compression`] codec, packages which
+    /// do not get smaller are sent uncompressed. The receiver decompresses them
+    /// as long as it was built with the codec's cargo feature
+    pub fn set_compression(&mut self, compression: u8) {
+        self.compression = compression;
+    }
+
+    pub fn compression(&self) -> u8 {
+        self.compression
     }
 
     /// # Packages streaming protocol
@@ -148,183 +195,1462 @@ impl Packages {
     /// - B -> A `[BYTE]`
     ///     - Response byte says whether it can continue or not
     ///     - [0] continue
-    ///     - [1] stop
-    pub fn write_to(self, tcp_stream: &mut TcpStream) {
-        let data_length = self.data.len();
-        let data_vec = data_to_vec_data(self.data, self.packages_size.get_value());
-        let packages = data_to_packages(data_vec);
+    ///     - [1] cancel, reported as [`MtpError::Cancelled`]
+    ///     - [2] pause, wait for another response byte
+    pub fn write_to<S: Read + Write>(mut self, stream: &mut S) -> Result<()> {
+        let data = std::mem::take(&mut self.data);
+
+        self.write_from_reader(
+            stream,
+            data.as_slice(),
+            Some(data.len()),
+            WriteOptions::new(),
+        )
+    }
 
-        let mut sent = 0;
-        let mut bytes_sent = 0;
+    /// Same as [`Packages::write_to`] but the data is read from the reader one package
+    /// at a time, at most two packages are kept in memory no matter the transfer size.
+    /// The options seal the packages or make the transfer resumable, see [`WriteOptions`].
+    ///
+    /// The length hint is only used for the reports, `None` reports a total of 0
+    /// ```ignore
+    /// let file = File::open("music.flac")?;
+    /// let length = file.metadata()?.len() as usize;
+    ///
+    /// let options = WriteOptions::new();
+    /// Packages::new(vec![]).write_from_reader(&mut stream, file, Some(length), options)?;
+    /// ```
+    pub fn write_from_reader<S: Read + Write, R: Read>(
+        mut self,
+        stream: &mut S,
+        mut reader: R,
+        length_hint: Option<usize>,
+        options: WriteOptions,
+    ) -> Result<()> {
+        let mut writer = PackagesSender::new(
+            &mut self,
+            options.session_key.as_ref(),
+            options.max_package_size,
+            length_hint,
+        )?;
 
-        let mut skips_report_count = 0;
-        let mut skip_report = 1;
+        if let Some(transfer_id) = options.transfer_id {
+            stream.write_all(transfer_id.as_bytes())?;
 
-        if self.reports_speed.is_some() {
-            skip_report = self.reports_speed.unwrap().apply_multiplier(packages.len());
+            let progress = TransferProgress::from_stream(stream)?;
+            skip_persisted(&mut reader, &progress, writer.fixed_package_size())?;
+            writer.resume(progress);
         }
 
-        let mut batch_count: usize = 0;
+        stream.write_all(&writer.header())?;
 
-        // println!("sending batch {}", self.batch_size.to_value());
+        let mut chunks = Chunks::new(reader);
 
-        tcp_stream
-            .write(&[self.batch_size.to_value()])
-            .expect("Expected to write batch size");
+        loop {
+            let (data, last) = chunks.next(writer.package_size())?;
 
-        for (i, package) in packages.iter().enumerate() {
-            batch_count += 1;
-            skips_report_count += 1;
-            bytes_sent += package.data.len();
+            let (buffer, expects_response) = writer.frame(data, last)?;
+            stream.write_all(&buffer)?;
 
-            let mut buffer = package.clone().to_buffer();
+            if expects_response {
+                read_response(stream)?;
+                writer.acknowledged();
+            }
 
-            if i >= packages.len() - 1 {
-                buffer.push(0);
-            } else {
-                buffer.push(1);
+            writer.report();
+
+            if last {
+                break;
             }
+        }
+
+        read_response(stream)?;
+
+        writer.finish();
 
-            // println!("{:?}", buffer);
-
-            tcp_stream
-                .write(&buffer)
-                .expect("Expected to be able to write to stream");
-
-            // println!("batch track {}", batch_count % self.batch_size.to_value() as usize);
-            if batch_count % self.batch_size.to_value() as usize == 0 {
-                // println!(
-                //     "Writing Batch Size affirmation : {} {} - {}",
-                //     batch_count,
-                //     self.batch_size.to_value() as usize,
-                //     batch_count % self.batch_size.to_value() as usize
-                // );
-                let mut response = [0; 1];
-                tcp_stream
-                    .read(&mut response)
-                    .expect("Expected to read response");
-                // println!("Server response {:?}", response);
+        Ok(())
+    }
+
+    pub fn read_from<S: Read + Write>(stream: &mut S) -> Result<Self> {
+        let mut packages = Self::new(vec![]);
+
+        Self::read_into(stream, &mut packages.data, ReadOptions::new())?;
+
+        Ok(packages)
+    }
+
+    /// Same as [`Packages::read_from`] but the data of every package is written to the
+    /// sink as soon as it arrives instead of being kept in memory, returns the amount
+    /// of bytes written during this call. The options open sealed packages, control,
+    /// report or resume the transfer, see [`ReadOptions`]
+    /// ```ignore
+    /// Packages::read_into(&mut stream, File::create("music.flac")?, ReadOptions::new())?;
+    /// ```
+    pub fn read_into<S: Read + Write, W: Write>(
+        stream: &mut S,
+        mut sink: W,
+        options: ReadOptions,
+    ) -> Result<usize> {
+        let mut written = 0;
+
+        let mut transfer = match options.log {
+            Some(log) => {
+                let transfer_id = Uuid::from_bytes(read_array::<S, 16>(stream)?);
+                let progress = log.get(&transfer_id);
+
+                stream.write_all(&progress.to_buffer()?)?;
+
+                Some((log, transfer_id, progress))
             }
+            None => None,
+        };
+        let resume_from = transfer.as_ref().map_or(0, |(_, _, progress)| progress.item);
 
-            sent += 1;
+        let mut receiver = PackagesReceiver::start(
+            stream,
+            options.session_key,
+            options.reporter,
+            options.max_package_size,
+        )?;
+        receiver.resume(resume_from);
 
-            if skips_report_count % skip_report == 0 {
-                if self.reports_callback.is_some() {
-                    self.reports_callback.unwrap()(PackagesReport {
-                        bytes_sent,
-                        sent,
-                        total: packages.len(),
-                        total_bytes: data_length,
-                    });
+        receive_packages(stream, receiver, options.control, |item, data| {
+            sink.write_all(&data).map_err(MtpError::Io)?;
+            written += data.len();
+
+            // Empty packages hold nothing to persist, the data is flushed before
+            // being recorded so the progress never covers data the sink still buffers
+            if let Some((log, transfer_id, progress)) = &mut transfer {
+                if !data.is_empty() {
+                    sink.flush().map_err(MtpError::Io)?;
+
+                    progress.item = item;
+                    progress.bytes += data.len();
+                    log.record(*transfer_id, *progress);
                 }
             }
+
+            Ok(())
+        })?;
+
+        sink.flush().map_err(MtpError::Io)?;
+
+        Ok(written)
+    }
+
+    /// Same as [`Packages::read_into`] but the data is pulled through
+    /// [`Read`], a package is only received once the previous one has been read
+    pub fn reader<S: Read + Write>(stream: &mut S) -> Result<PackagesReader<'_, S>> {
+        PackagesReader::new(stream, None, MAX_EXTENDED_LENGTH)
+    }
+
+    /// Same as [`Packages::reader`] for packages sealed with [`WriteOptions::session_key`]
+    pub fn encrypted_reader<'a, S: Read + Write>(
+        stream: &'a mut S,
+        session_key: &SessionKey,
+    ) -> Result<PackagesReader<'a, S>> {
+        PackagesReader::new(stream, Some(session_key.clone()), MAX_EXTENDED_LENGTH)
+    }
+}
+
+/// Receives every package left and hands out its item number and data
+fn receive_packages<S: Read + Write, F: FnMut(usize, Vec<u8>) -> Result<()>>(
+    stream: &mut S,
+    mut receiver: PackagesReceiver,
+    control: Option<&TransferControl>,
+    mut on_package: F,
+) -> Result<()> {
+    loop {
+        let received = receive_package(stream, &mut receiver, control)?;
+        let more = received.more;
+
+        on_package(received.item, received.data)?;
+
+        if !more {
+            return Ok(());
         }
+    }
+}
+
+/// Receives a single package and answers it, the final response is written after the last one
+fn receive_package<S: Read + Write>(
+    stream: &mut S,
+    receiver: &mut PackagesReceiver,
+    control: Option<&TransferControl>,
+) -> Result<Received> {
+    let package = Package::read_limited(stream, receiver.max_data_length())?;
+    let [footer] = read_array::<S, 1>(stream)?;
+
+    let received = receiver.receive(package, footer)?;
 
-        // println!("pre exit response ");
+    if received.acknowledge {
+        match control {
+            Some(control) => control.acknowledge(stream)?,
+            None => stream.write_all(&[response::CONTINUE])?,
+        }
+    }
 
-        let mut response = [0; 1];
-        tcp_stream
-            .read(&mut response)
-            .expect("Expected to read response");
+    if !received.more {
+        receiver.verify_digest(&read_bytes(stream, receiver.digest_length())?)?;
 
-        // println!("total data writen {}", bytes_sent);
+        stream.write_all(&[response::CONTINUE])?;
+    }
 
-        // println!("exited");
-        if self.reports_callback.is_some() {
-            self.reports_callback.unwrap()(PackagesReport {
-                bytes_sent,
-                sent,
-                total: packages.len(),
-                total_bytes: data_length,
-            });
+    Ok(received)
+}
+
+/// Data of incoming packages pulled through [`Read`], created with [`Packages::reader`]
+pub struct PackagesReader<'a, S: Read + Write> {
+    stream: &'a mut S,
+    receiver: PackagesReceiver,
+    control: Option<TransferControl>,
+    data: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+impl<'a, S: Read + Write> PackagesReader<'a, S> {
+    pub(crate) fn new(
+        stream: &'a mut S,
+        session_key: Option<SessionKey>,
+        max_package_size: usize,
+    ) -> Result<Self> {
+        let header = read_array::<S, 1>(stream)?;
+        let receiver = PackagesReceiver::new(header, session_key, max_package_size)?;
+
+        Ok(Self {
+            stream,
+            receiver,
+            control: None,
+            data: vec![],
+            position: 0,
+            done: false,
+        })
+    }
+
+    /// Lets the transfer be paused, resumed or cancelled while it is read
+    pub fn set_transfer_control(&mut self, control: TransferControl) {
+        self.control = Some(control);
+    }
+
+    /// Reports the progress of the transfer as packages are read
+    pub fn set_reporter(&mut self, reporter: PackagesReporter) {
+        self.receiver.set_reporter(reporter);
+    }
+}
+
+impl<S: Read + Write> Read for PackagesReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Packages may be empty, keep receiving until there is data or none is left
+        while self.position == self.data.len() {
+            if self.done {
+                return Ok(0);
+            }
+
+            let received = receive_package(self.stream, &mut self.receiver, self.control.as_ref())?;
+
+            self.done = !received.more;
+            self.data = received.data;
+            self.position = 0;
         }
+
+        let length = buf.len().min(self.data.len() - self.position);
+        buf[..length].copy_from_slice(&self.data[self.position..self.position + length]);
+        self.position += length;
+
+        Ok(length)
     }
+}
 
-    pub fn read_from(tcp_stream: &mut TcpStream) -> Self {
-        let mut packages = Self::new(vec![]);
+/// Sending side of the packages protocol independent of the stream
+/// it is written to, shared by blocking and async streams
+pub(crate) struct PackagesSender<'a> {
+    session_key: Option<&'a SessionKey>,
+    package_size: usize,
+    max_package_size: usize,
+    adaptive_package_size: bool,
+    batch_size: PackagesBatchSize,
+    batch_length: usize,
+    batch_count: usize,
+    batch_started: Instant,
+    batch_bytes: usize,
+    throughput: f64,
+    channel: u32,
+    checksum: u8,
+    digest: u8,
+    compression: u8,
+    hasher: Sha256,
+    reports: Reports,
+    sent: usize,
+    bytes_sent: usize,
+    wire_bytes_sent: usize,
+    total: usize,
+    total_bytes: usize,
+}
 
-        let mut batch_size_byte = [0; 1];
-        tcp_stream
-            .read(&mut batch_size_byte)
-            .expect("Expected to read batch size byte");
+impl<'a> PackagesSender<'a> {
+    /// Takes over the reporter of the packages, fails when the package
+    /// or batch size is not valid, see [`PackageSize::check`].
+    /// No package carries more than `max_package_size` bytes, adaptive sizes included
+    pub(crate) fn new(
+        packages: &mut Packages,
+        session_key: Option<&'a SessionKey>,
+        max_package_size: usize,
+        length_hint: Option<usize>,
+    ) -> Result<Self> {
+        packages.packages_size.check()?;
+        packages.batch_size.check()?;
 
-        let batch_size = PackagesBatchSize::from_value(batch_size_byte[0])
-            .expect("Expected valid number for batch size");
-        let mut batch_count: usize = 0;
+        // Sealing adds bytes to every package which must still fit its length header
+        let mut max_package_size = packages.packages_size.get_value().min(max_package_size);
+        if session_key.is_some() {
+            let length_header = if max_package_size <= MAX_HEADER_LENGTH {
+                MAX_HEADER_LENGTH
+            } else {
+                MAX_EXTENDED_LENGTH
+            };
+            max_package_size = max_package_size.min(length_header - SESSION_OVERHEAD);
+        }
 
-        loop {
-            let mut package = Package::from_stream(tcp_stream);
-            batch_count += 1;
-
-            // let data_length = package.data.len();
-
-            packages.data.append(&mut package.data);
-
-            let mut footer_byte = [0; 1];
-
-            tcp_stream
-                .read(&mut footer_byte)
-                .expect("Expected to be able to read from stream");
-
-            // println!(
-            //     "- Regular : {} {} - {} : {} / {}",
-            //     batch_count,
-            //     batch_size.to_value() as usize,
-            //     batch_count % batch_size.to_value() as usize,
-            //     data_length,
-            //     packages.data.len()
-            // );
-
-            if batch_count % batch_size.to_value() as usize == 0 {
-                // println!("Batch counted {}", batch_count);
-                // println!(
-                //     "Writing Batch Size affirmation : {} {} - {}",
-                //     batch_count,
-                //     batch_size.to_value() as usize,
-                //     batch_count % batch_size.to_value() as usize
-                // );
-                tcp_stream
-                    .write(&[0])
-                    .expect("Expected to be able to write to stream");
+        let adaptive_package_size = packages.packages_size == PackageSize::ADAPTIVE;
+        let package_size = if adaptive_package_size {
+            PackageSize::MEDIUM.get_value().min(max_package_size)
+        } else {
+            max_package_size
+        };
+
+        let batch_length = match packages.batch_size {
+            PackagesBatchSize::ADAPTIVE => PackagesBatchSize::SMALL.to_value(),
+            batch_size => batch_size.to_value(),
+        };
+
+        let total_bytes = length_hint.unwrap_or(0);
+        let total = total_bytes.div_ceil(package_size).max(1);
+
+        Ok(Self {
+            session_key,
+            package_size,
+            max_package_size,
+            adaptive_package_size,
+            batch_size: packages.batch_size,
+            batch_length: batch_length as usize,
+            batch_count: 0,
+            batch_started: Instant::now(),
+            batch_bytes: 0,
+            throughput: 0.0,
+            channel: 0,
+            checksum: packages.checksum,
+            digest: packages.digest,
+            compression: packages.compression,
+            hasher: Sha256::new(),
+            reports: Reports::new(std::mem::take(&mut packages.reporter), total),
+            sent: 0,
+            bytes_sent: 0,
+            wire_bytes_sent: 0,
+            total: length_hint.map_or(0, |_| total),
+            total_bytes,
+        })
+    }
+
+    /// Largest amount of data carried by the next package
+    pub(crate) fn package_size(&self) -> usize {
+        self.package_size
+    }
+
+    /// Package size kept along the whole transfer, none when it is adaptive
+    pub(crate) fn fixed_package_size(&self) -> Option<usize> {
+        if self.adaptive_package_size {
+            None
+        } else {
+            Some(self.package_size)
+        }
+    }
+
+    /// Continues the numbering and the reports after the packages already persisted
+    pub(crate) fn resume(&mut self, progress: TransferProgress) {
+        self.sent = progress.item;
+        self.bytes_sent = progress.bytes;
+        self.reports.resume(progress.bytes);
+    }
+
+    /// Marks every package with the channel it is sent on
+    pub(crate) fn set_channel(&mut self, channel: u32) {
+        self.channel = channel;
+    }
+
+    /// Batch size byte sent before any package
+    pub(crate) fn header(&self) -> [u8; 1] {
+        [self.batch_size.to_value()]
+    }
+
+    /// Package buffer carrying the data followed by its footer byte, and
+    /// whether the receiver's response has to be read once it is written
+    pub(crate) fn frame(&mut self, data: Vec<u8>, last: bool) -> Result<(Vec<u8>, bool)> {
+        let item = u32::try_from(self.sent + 1).map_err(|_| MtpError::OversizedLength {
+            length: self.sent + 1,
+            max: u32::MAX as usize,
+        })?;
+
+        if self.batch_count == 0 {
+            self.batch_started = Instant::now();
+            self.batch_bytes = 0;
+        }
+
+        self.batch_count += 1;
+        self.bytes_sent += data.len();
+
+        if self.digest != digest::NONE {
+            self.hasher.update(&data);
+        }
+
+        let meta = PackageMeta {
+            channel: self.channel,
+            checksum: self.checksum,
+            digest: self.digest,
+            ..PackageMeta::new(item, PackageType::PACKAGES)
+        };
+
+        let package = Package::new(data, meta).compress(self.compression)?;
+
+        let mut buffer = match self.session_key {
+            Some(session_key) => package.seal(session_key)?.to_buffer()?,
+            None => package.to_buffer()?,
+        };
+
+        // Adaptive batches vary in length so their end is marked, the last
+        // package is answered by the final response
+        let adaptive_batch = matches!(self.batch_size, PackagesBatchSize::ADAPTIVE);
+        let end_of_batch = self.batch_count == self.batch_length;
+        let expects_response = end_of_batch && !(last && adaptive_batch);
+
+        buffer.push(if last {
+            footer::LAST
+        } else if end_of_batch && adaptive_batch {
+            footer::END_OF_BATCH
+        } else {
+            footer::MORE
+        });
+
+        // The digest of the whole transfer follows the footer of the last package
+        if last {
+            match self.digest {
+                digest::NONE => {}
+                digest::SHA256 => buffer.extend_from_slice(&self.hasher.clone().finish()),
+                _ => return Err(MtpError::MalformedHeader("unknown transfer digest")),
             }
+        }
 
-            // println!("footer byte {:?}", footer_byte);
-            if footer_byte[0] == 0 {
-                break;
+        self.wire_bytes_sent += buffer.len();
+        self.batch_bytes += buffer.len();
+
+        if end_of_batch {
+            self.batch_count = 0;
+        }
+
+        Ok((buffer, expects_response))
+    }
+
+    /// Takes in the receiver's response to a batch, adaptive sizes grow while
+    /// batches keep a short round trip and do not lose throughput, and shrink otherwise
+    pub(crate) fn acknowledged(&mut self) {
+        let round_trip = self.batch_started.elapsed();
+        let throughput = self.batch_bytes as f64 / round_trip.as_secs_f64().max(f64::EPSILON);
+
+        let grow = round_trip < TARGET_ROUND_TRIP && throughput >= self.throughput;
+        let shrink = round_trip > TARGET_ROUND_TRIP || throughput < self.throughput / 2.0;
+        self.throughput = throughput;
+
+        if !grow && !shrink {
+            return;
+        }
+
+        // Package sizes stay one below a power of two like the fixed sizes
+        if self.adaptive_package_size {
+            let package_size = if grow {
+                (self.package_size + 1) * 2 - 1
+            } else {
+                (self.package_size.div_ceil(2) - 1).max(PackageSize::SMALL.get_value())
+            };
+
+            self.package_size = package_size.min(self.max_package_size);
+        }
+
+        if matches!(self.batch_size, PackagesBatchSize::ADAPTIVE) {
+            self.batch_length = if grow {
+                (self.batch_length * 2).min(PackagesBatchSize::MAX.to_value() as usize)
+            } else {
+                (self.batch_length / 2).max(1)
+            };
+        }
+    }
+
+    /// Counts the last framed package as sent and reports it
+    pub(crate) fn report(&mut self) {
+        self.sent += 1;
+        self.send_report(false);
+    }
+
+    /// Reports the end of the transfer
+    pub(crate) fn finish(&mut self) {
+        self.send_report(true);
+    }
+
+    fn send_report(&mut self, last: bool) {
+        // Packages left are counted with the package size in use, which adaptive sizes change
+        let total = match self.total {
+            0 => 0,
+            _ => {
+                self.sent
+                    + self
+                        .total_bytes
+                        .saturating_sub(self.bytes_sent)
+                        .div_ceil(self.package_size)
             }
+        };
+
+        let report = PackagesReport {
+            bytes_sent: self.bytes_sent,
+            sent: self.sent,
+            total,
+            total_bytes: self.total_bytes,
+            wire_bytes_sent: self.wire_bytes_sent,
+            package_size: self.package_size,
+            batch_size: self.batch_length,
+            ..PackagesReport::default()
+        };
+
+        self.reports.report(report, last);
+    }
+}
+
+/// Package received by [`PackagesReceiver`]
+pub(crate) struct Received {
+    pub item: usize,
+    pub data: Vec<u8>,
+    /// Whether the batch is complete and has to be answered
+    pub acknowledge: bool,
+    /// Whether more packages follow
+    pub more: bool,
+}
+
+/// Receiving side of the packages protocol independent of the stream
+/// it is read from, shared by blocking and async streams
+pub(crate) struct PackagesReceiver {
+    session_key: Option<SessionKey>,
+    max_package_size: usize,
+    batch_size: PackagesBatchSize,
+    batch_count: usize,
+    next_item: usize,
+    digest: u8,
+    hasher: Sha256,
+    resumed: bool,
+    reports: Reports,
+    received: usize,
+    bytes_received: usize,
+    wire_bytes_received: usize,
+    batch_length: usize,
+    acknowledged_count: usize,
+}
+
+impl PackagesReceiver {
+    /// Starts reading from the batch size byte sent before any package,
+    /// packages whose data is longer than `max_package_size` are refused
+    pub(crate) fn new(
+        header: [u8; 1],
+        session_key: Option<SessionKey>,
+        max_package_size: usize,
+    ) -> Result<Self> {
+        let batch_size = PackagesBatchSize::from_value(header[0])?;
+
+        Ok(Self {
+            session_key,
+            max_package_size,
+            batch_size,
+            batch_count: 0,
+            next_item: 1,
+            digest: digest::NONE,
+            hasher: Sha256::new(),
+            resumed: false,
+            reports: Reports::new(PackagesReporter::default(), 0),
+            received: 0,
+            bytes_received: 0,
+            wire_bytes_received: 0,
+            batch_length: batch_size.to_value() as usize,
+            acknowledged_count: 0,
+        })
+    }
+
+    /// Reads the batch size byte from the stream and starts reading
+    fn start<S: Read>(
+        stream: &mut S,
+        session_key: Option<SessionKey>,
+        reporter: Option<PackagesReporter>,
+        max_package_size: usize,
+    ) -> Result<Self> {
+        let header = read_array::<S, 1>(stream)?;
+        let mut receiver = Self::new(header, session_key, max_package_size)?;
+
+        if let Some(reporter) = reporter {
+            receiver.set_reporter(reporter);
+        }
+
+        Ok(receiver)
+    }
+
+    /// Longest data a package may carry on the wire, sealing adds to the data
+    pub(crate) fn max_data_length(&self) -> usize {
+        if self.session_key.is_some() {
+            max_sealed_length(self.max_package_size)
+        } else {
+            self.max_package_size
+        }
+    }
+
+    /// Reports every package received, the receiver is not told
+    /// the total of the transfer so reports hold no total nor ETA
+    pub(crate) fn set_reporter(&mut self, reporter: PackagesReporter) {
+        self.reports = Reports::new(reporter, 0);
+    }
+
+    /// Expects the packages after the item already persisted
+    pub(crate) fn resume(&mut self, item: usize) {
+        self.next_item = item + 1;
+        self.resumed = item > 0;
+    }
+
+    /// Length of the digest following the footer of the last package
+    pub(crate) fn digest_length(&self) -> usize {
+        digest_length(self.digest)
+    }
+
+    /// Checks the digest sent after the last package against the data received
+    pub(crate) fn verify_digest(&self, transfer_digest: &[u8]) -> Result<()> {
+        if self.digest == digest::NONE || self.resumed {
+            return Ok(());
+        }
+
+        if self.hasher.clone().finish() != transfer_digest {
+            return Err(MtpError::DigestMismatch);
+        }
+
+        Ok(())
+    }
+
+    /// Takes in a package and its footer byte
+    pub(crate) fn receive(&mut self, package: Package, footer: u8) -> Result<Received> {
+        package.meta.check_type(PackageType::PACKAGES)?;
+
+        let item = package.meta.item as usize;
+        if item != self.next_item {
+            return Err(MtpError::MalformedHeader("package received out of order"));
+        }
+        self.next_item += 1;
+
+        // The footer byte follows the package
+        self.wire_bytes_received += package.wire_length() + 1;
+
+        self.digest = match package.meta.digest {
+            digest @ (digest::NONE | digest::SHA256) => digest,
+            _ => return Err(MtpError::MalformedHeader("unknown transfer digest")),
+        };
+
+        let package = match &self.session_key {
+            Some(session_key) => package.open(session_key)?,
+            None => package,
+        }
+        .decompress_limited(self.max_package_size)?;
+
+        if self.digest != digest::NONE {
+            self.hasher.update(&package.data);
+        }
+
+        self.batch_count += 1;
+
+        let adaptive_batch = matches!(self.batch_size, PackagesBatchSize::ADAPTIVE);
+
+        let acknowledge = if adaptive_batch {
+            footer == footer::END_OF_BATCH
+        } else {
+            self.batch_count
+                .is_multiple_of(self.batch_size.to_value() as usize)
+        };
+
+        let more = match footer {
+            footer::LAST => false,
+            footer::MORE => true,
+            footer::END_OF_BATCH if adaptive_batch => true,
+            _ => return Err(MtpError::MalformedHeader("unknown package footer byte")),
+        };
+
+        // Adaptive batches are only known to be over once acknowledged
+        if acknowledge && adaptive_batch {
+            self.batch_length = self.batch_count - self.acknowledged_count;
+            self.acknowledged_count = self.batch_count;
+        }
+
+        self.received += 1;
+        self.bytes_received += package.data.len();
+
+        let report = PackagesReport {
+            sent: self.received,
+            bytes_sent: self.bytes_received,
+            wire_bytes_sent: self.wire_bytes_received,
+            package_size: package.data.len(),
+            batch_size: self.batch_length,
+            ..PackagesReport::default()
+        };
+        self.reports.report(report, !more);
+
+        Ok(Received {
+            item,
+            data: package.data,
+            acknowledge,
+            more,
+        })
+    }
+}
+
+/// Length of the [`digest`] following the footer of the last package
+pub(crate) fn digest_length(digest: u8) -> usize {
+    match digest {
+        digest::SHA256 => 32,
+        _ => 0,
+    }
+}
+
+/// Reads the receiver's response, waiting for as long as the receiver keeps the transfer paused
+fn read_response<S: Read>(stream: &mut S) -> Result<()> {
+    while check_response(read_array::<S, 1>(stream)?[0])? {}
+
+    Ok(())
+}
+
+/// Discards the bytes the receiver already persisted from the reader
+fn skip_persisted<R: Read>(
+    reader: &mut R,
+    progress: &TransferProgress,
+    package_size: Option<usize>,
+) -> Result<()> {
+    // Only the last package may be shorter than the package size,
+    // adaptive package sizes vary so there is nothing to check
+    if let Some(package_size) = package_size {
+        if progress.bytes.div_ceil(package_size) != progress.item {
+            return Err(MtpError::Incompatible(
+                "transfer was persisted with another package size",
+            ));
+        }
+    }
+
+    let skipped = std::io::copy(&mut reader.take(progress.bytes as u64), &mut std::io::sink())?;
+    if skipped != progress.bytes as u64 {
+        return Err(MtpError::Incompatible(
+            "receiver persisted more data than the transfer holds",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Splits the data of a reader in the data of every package, a package is the
+/// last one once the reader has nothing left after it so a chunk is read ahead
+pub(crate) struct Chunks<R> {
+    reader: R,
+    /// Chunk read ahead along with the size it was read with
+    ahead: Option<(Vec<u8>, usize)>,
+}
+
+impl<R> Chunks<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Self {
+            reader,
+            ahead: None,
         }
+    }
 
-        // println!("Writing exit response");
-        tcp_stream.write(&[0]).unwrap();
+    /// Chunk read ahead and the size it was read with, none until the first chunk is read
+    pub(crate) fn take_ahead(&mut self) -> Option<(Vec<u8>, usize)> {
+        self.ahead.take()
+    }
 
-        packages
+    pub(crate) fn reader(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Keeps the chunk read after the data for the next package,
+    /// returns the data along with whether its package is the last one
+    pub(crate) fn advance(
+        &mut self,
+        data: Vec<u8>,
+        next: Vec<u8>,
+        size: usize,
+    ) -> (Vec<u8>, bool) {
+        let last = next.is_empty();
+
+        if !last {
+            self.ahead = Some((next, size));
+        }
+
+        (data, last)
     }
 }
 
-fn data_to_vec_data(data: Vec<u8>, max_package_size: usize) -> Vec<Vec<u8>> {
-    let mut i = 0;
-
-    data.split_inclusive(|_| {
-        i += 1;
-        i % max_package_size == 0
-    })
-    .collect::<Vec<&[u8]>>()
-    .iter()
-    .map(|i| (**i).to_vec())
-    .collect::<Vec<Vec<u8>>>()
+impl<R: Read> Chunks<R> {
+    /// Data of the next package, up to `size` bytes, along with whether it is the last one
+    pub(crate) fn next(&mut self, size: usize) -> Result<(Vec<u8>, bool)> {
+        let (data, read_size) = match self.take_ahead() {
+            Some(ahead) => ahead,
+            None => (read_chunk(self.reader(), size)?, size),
+        };
+
+        // A chunk shorter than its size means the reader ended
+        let next = if data.len() < read_size {
+            vec![]
+        } else {
+            read_chunk(self.reader(), size)?
+        };
+
+        Ok(self.advance(data, next, size))
+    }
 }
 
-fn data_to_packages(bytes: Vec<Vec<u8>>) -> Vec<Package> {
-    bytes
-        .iter()
-        .enumerate()
-        .map(|(i, package_bytes)| {
-            let meta_uuid = new_uuid(i + 1, vec![], typemarkers::PACKAGE, encryption::UNENCRYPTED);
+/// Reads up to `size` bytes, less only when the reader ends
+fn read_chunk<R: Read>(reader: &mut R, size: usize) -> Result<Vec<u8>> {
+    let mut chunk = vec![];
+    reader.take(size as u64).read_to_end(&mut chunk)?;
+
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use std::os::unix::net::UnixStream;
+
+    use crate::package::{
+        packages::{Packages, PackagesBatchSize},
+        PackageSize,
+    };
+
+    /// `length` bytes counting up from 0 and wrapping around
+    #[cfg(unix)]
+    fn counting(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i % 256) as u8).collect()
+    }
+
+    /// Sends on one end of a socket pair while the other end is read on another thread
+    #[cfg(unix)]
+    fn over_socket<T: Send + 'static>(
+        send: impl FnOnce(&mut UnixStream),
+        receive: impl FnOnce(&mut UnixStream) -> T + Send + 'static,
+    ) -> T {
+        let (mut sender, mut receiver) = UnixStream::pair().unwrap();
+
+        let receiver = std::thread::spawn(move || receive(&mut receiver));
+        send(&mut sender);
+
+        receiver.join().unwrap()
+    }
+
+    /// Packages read back from [`Packages::write_to`]
+    #[cfg(unix)]
+    fn round_trip(packages: Packages) -> Packages {
+        over_socket(
+            |sender| packages.write_to(sender).unwrap(),
+            |receiver| Packages::read_from(receiver).unwrap(),
+        )
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn packages_over_unix_socket() {
+        let data = counting(50_000);
+        let mut packages = Packages::new(data.clone());
+        packages.set_package_size(PackageSize::SMALL);
+        packages.set_batch_size(PackagesBatchSize::LARGE);
+
+        assert_eq!(round_trip(packages).data, data);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn custom_sizes() {
+        let data = counting(5_000_000);
+        let mut packages = Packages::new(data.clone());
+        packages.set_package_size(PackageSize::CUSTOM(usize::pow(2, 20)));
+        packages.set_batch_size(PackagesBatchSize::CUSTOM(32));
+
+        assert_eq!(round_trip(packages).data, data);
+        assert!(matches!(
+            PackagesBatchSize::from_value(32),
+            Ok(PackagesBatchSize::CUSTOM(32))
+        ));
+    }
+
+    #[test]
+    fn invalid_custom_sizes() {
+        use std::io::Cursor;
+
+        use crate::{error::MtpError, utils::MAX_EXTENDED_LENGTH};
+
+        for size in [0, MAX_EXTENDED_LENGTH + 1] {
+            let mut packages = Packages::new(vec![1; 10]);
+            packages.set_package_size(PackageSize::CUSTOM(size));
+
+            assert!(matches!(
+                packages.write_to(&mut Cursor::new(vec![])),
+                Err(MtpError::InvalidPackageSize(invalid)) if invalid == size
+            ));
+        }
+
+        let mut packages = Packages::new(vec![1; 10]);
+        packages.set_batch_size(PackagesBatchSize::CUSTOM(0));
+
+        assert!(matches!(
+            packages.write_to(&mut Cursor::new(vec![])),
+            Err(MtpError::InvalidBatchSize(0))
+        ));
+        assert!(PackageSize::CUSTOM(1).check().is_ok());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn packages_from_reader() {
+        use std::io::Cursor;
+
+        use crate::package::options::WriteOptions;
+
+        let data = counting(50_000);
+        let mut packages = Packages::new(vec![]);
+        packages.set_package_size(PackageSize::MEDIUM);
+
+        // Short reads on the reader side must not shrink the packages
+        let reader = crate::tests::Trickle(Cursor::new(data.clone()));
+
+        let received = over_socket(
+            |sender| {
+                packages
+                    .write_from_reader(sender, reader, Some(data.len()), WriteOptions::new())
+                    .unwrap()
+            },
+            |receiver| Packages::read_from(receiver).unwrap(),
+        );
+
+        assert_eq!(received.data, data);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn empty_packages() {
+        assert!(round_trip(Packages::new(vec![])).data.is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn packages_into_sink() {
+        use crate::package::options::ReadOptions;
+
+        let data = counting(50_000);
+        let mut packages = Packages::new(data.clone());
+        packages.set_package_size(PackageSize::MEDIUM);
+        packages.set_batch_size(PackagesBatchSize::SMALL);
+
+        let (written, sink) = over_socket(
+            |sender| packages.write_to(sender).unwrap(),
+            |receiver| {
+                let mut sink = vec![];
+                let written = Packages::read_into(receiver, &mut sink, ReadOptions::new()).unwrap();
+
+                (written, sink)
+            },
+        );
+
+        assert_eq!(written, data.len());
+        assert_eq!(sink, data);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn packages_through_reader() {
+        use std::io::Read;
+
+        let data = counting(50_000);
+        let mut packages = Packages::new(data.clone());
+        packages.set_package_size(PackageSize::SMALL);
+
+        let received = over_socket(
+            |sender| packages.write_to(sender).unwrap(),
+            |receiver| {
+                let mut reader = Packages::reader(receiver).unwrap();
+
+                // Reads smaller than a package must not lose any data
+                let mut head = [0; 100];
+                reader.read_exact(&mut head).unwrap();
+
+                let mut rest = vec![];
+                reader.read_to_end(&mut rest).unwrap();
+
+                [head.to_vec(), rest].concat()
+            },
+        );
+
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn controlled_transfers() {
+        use crate::{
+            error::MtpError,
+            package::{control::TransferControl, options::ReadOptions},
+        };
+
+        let data = counting(50_000);
+
+        let transfer = |control: TransferControl| {
+            let mut packages = Packages::new(data.clone());
+            packages.set_package_size(PackageSize::MEDIUM);
+            packages.set_batch_size(PackagesBatchSize::TINY);
+
+            let mut sent = None;
+            let received = over_socket(
+                |sender| sent = Some(packages.write_to(sender)),
+                move |receiver| {
+                    let mut received = vec![];
+                    let options = ReadOptions::new().control(&control);
+
+                    Packages::read_into(receiver, &mut received, options).map(|_| received)
+                },
+            );
+
+            (sent.unwrap(), received)
+        };
+
+        // A paused transfer completes once resumed
+        let control = TransferControl::new();
+        control.pause();
+
+        let resume = control.clone();
+        let resumer = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            resume.resume();
+        });
+
+        let (sent, received) = transfer(control);
+        resumer.join().unwrap();
+
+        sent.unwrap();
+        assert_eq!(received.unwrap(), data);
+
+        // A cancelled transfer stops both parties with the same outcome
+        let control = TransferControl::new();
+        control.cancel();
+
+        let (sent, received) = transfer(control);
+
+        assert!(matches!(sent, Err(MtpError::Cancelled)));
+        assert!(matches!(received, Err(MtpError::Cancelled)));
+    }
 
-            Package {
-                data: package_bytes.clone(),
-                meta_uuid,
+    #[test]
+    #[cfg(unix)]
+    fn resume_interrupted_transfer() {
+        use std::{io, io::Write};
+
+        use uuid::Uuid;
+
+        use crate::{
+            error::MtpError,
+            package::{
+                options::{ReadOptions, WriteOptions},
+                transfer::{TransferLog, TransferProgress},
+            },
+        };
+
+        /// Sink buffering writes until flushed, running out of space after `limit` bytes
+        struct FullSink<'a> {
+            sink: &'a mut Vec<u8>,
+            buffered: Vec<u8>,
+            limit: usize,
+        }
+
+        impl Write for FullSink<'_> {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.buffered.extend_from_slice(buf);
+                Ok(buf.len())
             }
-        })
-        .collect::<Vec<Package>>()
+
+            fn flush(&mut self) -> io::Result<()> {
+                if self.sink.len() + self.buffered.len() > self.limit {
+                    return Err(io::Error::other("disk full"));
+                }
+
+                self.sink.append(&mut self.buffered);
+                Ok(())
+            }
+        }
+
+        let data = counting(50_000);
+        let transfer_id = Uuid::new_v4();
+
+        let send = |mut sender: UnixStream, data: Vec<u8>| {
+            std::thread::spawn(move || {
+                let mut packages = Packages::new(data);
+                packages.set_package_size(PackageSize::MEDIUM);
+                let data = std::mem::take(&mut packages.data);
+                let options = WriteOptions::new().resumable(transfer_id);
+
+                packages.write_from_reader(&mut sender, data.as_slice(), Some(data.len()), options)
+            })
+        };
+
+        let mut log = TransferLog::new();
+        let mut received = vec![];
+
+        // First attempt stops after 4 packages of 4095 bytes
+        let (sender, mut receiver) = UnixStream::pair().unwrap();
+        let sending = send(sender, data.clone());
+
+        let sink = FullSink {
+            sink: &mut received,
+            buffered: vec![],
+            limit: 20_000,
+        };
+        assert!(matches!(
+            Packages::read_into(&mut receiver, sink, ReadOptions::new().resumable(&mut log)),
+            Err(MtpError::Io(_))
+        ));
+        drop(receiver);
+
+        assert!(sending.join().unwrap().is_err());
+        assert_eq!(log.get(&transfer_id), TransferProgress { item: 4, bytes: 16380 });
+
+        // Second attempt only sends what is missing
+        let (sender, mut receiver) = UnixStream::pair().unwrap();
+        let sending = send(sender, data.clone());
+
+        let options = ReadOptions::new().resumable(&mut log);
+        let written = Packages::read_into(&mut receiver, &mut received, options).unwrap();
+        sending.join().unwrap().unwrap();
+
+        assert_eq!(written, data.len() - 16380);
+        assert_eq!(received, data);
+        assert_eq!(log.get(&transfer_id).bytes, data.len());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn packages_with_checksums_and_digest() {
+        use crate::package::meta::{checksum, digest};
+
+        let data = counting(50_000);
+        let mut packages = Packages::new(data.clone());
+        packages.set_package_size(PackageSize::MEDIUM);
+        packages.set_checksum(checksum::CRC32C);
+        packages.set_transfer_digest(digest::SHA256);
+
+        assert_eq!(round_trip(packages).data, data);
+    }
+
+    #[test]
+    fn corrupted_transfers_are_detected() {
+        use std::io::{Cursor, Read, Write};
+
+        use crate::{
+            error::MtpError,
+            package::meta::{checksum, digest, PackageType},
+        };
+
+        /// In-memory stream reading from `incoming` and recording what is written
+        struct Wire {
+            incoming: Cursor<Vec<u8>>,
+            outgoing: Vec<u8>,
+        }
+
+        impl Read for Wire {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.incoming.read(buf)
+            }
+        }
+
+        impl Write for Wire {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.outgoing.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // Writes 4 packages of 255 bytes and flips a bit in the data of the third one
+        let corrupted_transfer = |checksum: u8, digest: u8| {
+            let mut packages = Packages::new(vec![3; 1000]);
+            packages.set_package_size(PackageSize::SMALL);
+            packages.set_checksum(checksum);
+            packages.set_transfer_digest(digest);
+
+            let mut sender = Wire {
+                incoming: Cursor::new(vec![0]),
+                outgoing: vec![],
+            };
+            packages.write_to(&mut sender).unwrap();
+
+            let package_length = 16 + 3 + 255 + if checksum == checksum::NONE { 0 } else { 4 } + 1;
+            sender.outgoing[1 + 2 * package_length + 19 + 100] ^= 1;
+
+            Packages::read_from(&mut Wire {
+                incoming: Cursor::new(sender.outgoing),
+                outgoing: vec![],
+            })
+        };
+
+        assert!(matches!(
+            corrupted_transfer(checksum::CRC32C, digest::NONE),
+            Err(MtpError::ChecksumMismatch { item: 3 })
+        ));
+        assert!(matches!(
+            corrupted_transfer(checksum::NONE, digest::SHA256),
+            Err(MtpError::DigestMismatch)
+        ));
+        assert!(corrupted_transfer(checksum::NONE, digest::NONE).is_ok());
+
+        // The first package claims to be sent on its own
+        let mut sender = Wire {
+            incoming: Cursor::new(vec![0]),
+            outgoing: vec![],
+        };
+        Packages::new(vec![3; 10]).write_to(&mut sender).unwrap();
+        sender.outgoing[1 + 14] = PackageType::PACKAGE.to_value();
+
+        assert!(matches!(
+            Packages::read_from(&mut Wire {
+                incoming: Cursor::new(sender.outgoing),
+                outgoing: vec![],
+            }),
+            Err(MtpError::MalformedHeader("unexpected package type"))
+        ));
+    }
+
+    #[test]
+    #[cfg(all(unix, any(feature = "zstd", feature = "lz4")))]
+    fn compressed_packages() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use crate::package::{meta::compression, report::PackagesReport};
+
+        static WIRE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+        let data = b"{\"level\":\"info\",\"message\":\"package sent\"}\n".repeat(2000);
+        let mut packages = Packages::new(data.clone());
+        packages.set_package_size(PackageSize::LARGE);
+        packages.set_compression(if cfg!(feature = "zstd") {
+            compression::ZSTD
+        } else {
+            compression::LZ4
+        });
+        packages.listen_reports(|report: PackagesReport| {
+            WIRE_BYTES.store(report.wire_bytes_sent, Ordering::SeqCst);
+        });
+
+        assert_eq!(round_trip(packages).data, data);
+        assert!(WIRE_BYTES.load(Ordering::SeqCst) < data.len() / 4);
+    }
+
+    #[test]
+    #[cfg(all(unix, any(feature = "zstd", feature = "lz4")))]
+    fn compressed_extended_packages() {
+        use crate::package::meta::compression;
+
+        let codecs = [
+            #[cfg(feature = "zstd")]
+            compression::ZSTD,
+            #[cfg(feature = "lz4")]
+            compression::LZ4,
+        ];
+
+        // Decompresses past the 3 bytes length header
+        let data = counting(20 * usize::pow(2, 20));
+
+        for codec in codecs {
+            let mut packages = Packages::new(data.clone());
+            packages.set_package_size(PackageSize::CUSTOM(usize::pow(2, 25)));
+            packages.set_compression(codec);
+
+            assert_eq!(round_trip(packages).data, data);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn options_combine() {
+        use std::sync::mpsc;
+
+        use uuid::Uuid;
+
+        use crate::{
+            crypto::{generate_key_share, SessionKey},
+            package::{
+                control::TransferControl,
+                options::{ReadOptions, WriteOptions},
+                report::PackagesReporter,
+                transfer::TransferLog,
+            },
+        };
+
+        let data = counting(50_000);
+        let session_key = SessionKey::derive(&generate_key_share(), &generate_key_share());
+        let transfer_id = Uuid::new_v4();
+
+        let mut packages = Packages::new(vec![]);
+        packages.set_package_size(PackageSize::MEDIUM);
+        let options = WriteOptions::new()
+            .session_key(&session_key)
+            .resumable(transfer_id);
+
+        let (reports, reported) = mpsc::channel();
+        let reporter = PackagesReporter::new(move |report| reports.send(report).unwrap());
+
+        let sent = data.clone();
+        let (written, received, log) = over_socket(
+            move |sender| {
+                packages
+                    .write_from_reader(sender, sent.as_slice(), Some(sent.len()), options)
+                    .unwrap()
+            },
+            move |receiver| {
+                let control = TransferControl::new();
+                let (mut log, mut received) = (TransferLog::new(), vec![]);
+
+                let options = ReadOptions::new()
+                    .session_key(&session_key)
+                    .control(&control)
+                    .reporter(reporter)
+                    .resumable(&mut log);
+
+                let written = Packages::read_into(receiver, &mut received, options).unwrap();
+
+                (written, received, log)
+            },
+        );
+
+        assert_eq!((written, received), (data.len(), data.clone()));
+        assert_eq!(log.get(&transfer_id).bytes, data.len());
+        assert_eq!(reported.into_iter().last().unwrap().bytes_sent, data.len());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn reports_on_both_sides() {
+        use std::{sync::mpsc, time::Duration};
+
+        use crate::package::{
+            options::ReadOptions,
+            report::{PackageReportSpeed, PackagesReporter},
+        };
+
+        let data = counting(100_000);
+        let mut packages = Packages::new(data.clone());
+        packages.set_package_size(PackageSize::MEDIUM);
+        packages.set_report_speed(PackageReportSpeed::FASTEST);
+
+        // Closures capture the sending end of a channel
+        let (sent_reports, sent) = mpsc::channel();
+        packages.listen_reports(move |report| sent_reports.send(report).unwrap());
+
+        let (received_reports, received) = mpsc::channel();
+        let mut reporter =
+            PackagesReporter::new(move |report| received_reports.send(report).unwrap());
+        reporter.set_report_interval(Duration::from_secs(60));
+
+        let received_packages = over_socket(
+            |sender| packages.write_to(sender).unwrap(),
+            move |receiver| {
+                let mut received = vec![];
+                let options = ReadOptions::new().reporter(reporter);
+                Packages::read_into(receiver, &mut received, options).unwrap();
+
+                received
+            },
+        );
+
+        assert_eq!(received_packages, data);
+
+        let sent = sent.into_iter().collect::<Vec<_>>();
+        let last = sent.last().unwrap();
+
+        assert_eq!(sent.len(), data.len().div_ceil(PackageSize::MEDIUM.get_value()) + 1);
+        assert_eq!((last.bytes_sent, last.total_bytes), (data.len(), data.len()));
+        assert_eq!(last.eta, Some(Duration::ZERO));
+        assert!(last.average_throughput > 0.0);
+
+        // Throttled to the first report and the last one, the receiver is never told the total
+        let received = received.into_iter().collect::<Vec<_>>();
+
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1].bytes_sent, data.len());
+        assert!(received[1].wire_bytes_sent > data.len());
+        assert_eq!((received[1].total, received[1].eta), (0, None));
+    }
+
+    #[test]
+    fn adaptive_batches_are_marked() {
+        use crate::{
+            package::packages::{footer, PackagesSender},
+            utils::MAX_EXTENDED_LENGTH,
+        };
+
+        let mut packages = Packages::new(vec![]);
+        packages.set_package_size(PackageSize::ADAPTIVE);
+        packages.set_batch_size(PackagesBatchSize::ADAPTIVE);
+
+        let mut writer =
+            PackagesSender::new(&mut packages, None, MAX_EXTENDED_LENGTH, None).unwrap();
+        assert_eq!(writer.header(), [0]);
+        assert_eq!(writer.package_size(), PackageSize::MEDIUM.get_value());
+        assert_eq!(writer.fixed_package_size(), None);
+
+        // The first batch is 4 packages long
+        let footers = (0..4)
+            .map(|_| {
+                let (buffer, expects_response) = writer.frame(vec![1; 10], false).unwrap();
+                (*buffer.last().unwrap(), expects_response)
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(footers[..3], [(footer::MORE, false); 3]);
+        assert_eq!(footers[3], (footer::END_OF_BATCH, true));
+
+        // A quick round trip grows both sizes
+        writer.acknowledged();
+        assert_eq!(writer.package_size(), PackageSize::MEDIUM.get_value() * 2 + 1);
+
+        let (buffer, expects_response) = writer.frame(vec![1; 10], true).unwrap();
+        assert_eq!((*buffer.last().unwrap(), expects_response), (footer::LAST, false));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn adaptive_packages() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use crate::package::report::PackagesReport;
+
+        static LARGEST_PACKAGE: AtomicUsize = AtomicUsize::new(0);
+        static LARGEST_BATCH: AtomicUsize = AtomicUsize::new(0);
+
+        let data = (0..4_000_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let mut packages = Packages::new(data.clone());
+        packages.set_package_size(PackageSize::ADAPTIVE);
+        packages.set_batch_size(PackagesBatchSize::ADAPTIVE);
+        packages.listen_reports(|report: PackagesReport| {
+            LARGEST_PACKAGE.fetch_max(report.package_size, Ordering::SeqCst);
+            LARGEST_BATCH.fetch_max(report.batch_size, Ordering::SeqCst);
+        });
+
+        assert_eq!(round_trip(packages).data, data);
+        assert!(LARGEST_PACKAGE.load(Ordering::SeqCst) > PackageSize::MEDIUM.get_value());
+        assert!(
+            LARGEST_BATCH.load(Ordering::SeqCst) > PackagesBatchSize::SMALL.to_value() as usize
+        );
+    }
 }
@@ -0,0 +1,284 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Called with the reports of a transfer, closures can capture
+/// state such as a progress bar or the sender of a channel
+pub type PackageReportCallback = Box<dyn FnMut(PackagesReport) + Send>;
+
+/// Progress of a transfer, reported by the sender and by receivers given a [`PackagesReporter`]
+#[derive(Debug, Clone, Default)]
+pub struct PackagesReport {
+    /// Packages sent, or received when reported by the receiver
+    pub sent: usize,
+    /// Packages of the whole transfer, 0 when unknown as the receiver is never told
+    pub total: usize,
+    /// Data bytes sent before compression
+    pub bytes_sent: usize,
+    /// Data bytes of the whole transfer, 0 when unknown
+    pub total_bytes: usize,
+    /// Bytes actually written to the stream, compressed data and framing included
+    pub wire_bytes_sent: usize,
+    /// Data bytes of the packages being sent, changes along the
+    /// transfer with [`super::PackageSize::ADAPTIVE`]
+    pub package_size: usize,
+    /// Packages of the batch being sent, changes along the
+    /// transfer with [`super::packages::PackagesBatchSize::ADAPTIVE`]
+    pub batch_size: usize,
+    /// Time since the transfer started
+    pub elapsed: Duration,
+    /// Data bytes per second since the previous report
+    pub throughput: f64,
+    /// Data bytes per second since the transfer started
+    pub average_throughput: f64,
+    /// Time left at the average throughput, none while the total is unknown
+    pub eta: Option<Duration>,
+}
+
+/// Slows down reports by skipping reporting certain ones
+/// - fastest: No skips
+/// - fast: Skips every 1%
+/// - steady: Skips every 5%
+/// - slow: Skips every 10%
+/// - slowest: FAST: Skips every 15%
+///
+/// Note: If the total packages is too small it will not be noticable
+#[derive(Debug, Default)]
+pub enum PackageReportSpeed {
+    FASTEST,
+    FAST,
+    #[default]
+    STEADY,
+    SLOW,
+    SLOWEST,
+}
+
+impl PackageReportSpeed {
+    pub fn apply_multiplier(&self, value: usize) -> usize {
+        match self {
+            Self::FASTEST => 1,
+            Self::FAST => (value as f32 * 0.01).ceil() as usize,
+            Self::STEADY => (value as f32 * 0.05).ceil() as usize,
+            Self::SLOW => (value as f32 * 0.1).ceil() as usize,
+            Self::SLOWEST => (value as f32 * 0.15).ceil() as usize,
+        }
+    }
+}
+
+/// Where the reports of a transfer go and how often they are sent,
+/// the last report of a transfer is always sent
+/// ```ignore
+/// let (sender, progress) = std::sync::mpsc::channel();
+///
+/// let mut reporter = PackagesReporter::new(move |report| sender.send(report).unwrap());
+/// reporter.set_report_interval(Duration::from_millis(500));
+///
+/// stream.receive_into(file, ReadOptions::new().reporter(reporter))?;
+/// ```
+pub struct PackagesReporter {
+    callback: Option<PackageReportCallback>,
+    speed: Option<PackageReportSpeed>,
+    interval: Option<Duration>,
+}
+
+impl Default for PackagesReporter {
+    /// Reports nowhere at [`PackageReportSpeed::STEADY`]
+    fn default() -> Self {
+        Self {
+            callback: None,
+            speed: Some(PackageReportSpeed::default()),
+            interval: None,
+        }
+    }
+}
+
+impl fmt::Debug for PackagesReporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PackagesReporter")
+            .field("listening", &self.callback.is_some())
+            .field("speed", &self.speed)
+            .field("interval", &self.interval)
+            .finish()
+    }
+}
+
+impl PackagesReporter {
+    pub fn new<F: FnMut(PackagesReport) + Send + 'static>(callback: F) -> Self {
+        let mut reporter = Self::default();
+        reporter.listen(callback);
+
+        reporter
+    }
+
+    pub fn listen<F: FnMut(PackagesReport) + Send + 'static>(&mut self, callback: F) {
+        self.callback = Some(Box::new(callback));
+    }
+
+    /// Skips reports depending on the total of packages, receivers
+    /// do not know the total so they only skip by interval
+    pub fn set_report_speed(&mut self, speed: PackageReportSpeed) {
+        self.speed = Some(speed);
+    }
+
+    /// Sends at most one report per interval, on top of the reports skipped by the speed
+    pub fn set_report_interval(&mut self, interval: Duration) {
+        self.interval = Some(interval);
+    }
+}
+
+/// Fills in the timings of the reports of a transfer and
+/// hands them to the reporter as often as it asks for
+pub(crate) struct Reports {
+    reporter: PackagesReporter,
+    skip: usize,
+    started: Instant,
+    start_bytes: usize,
+    last: Option<(Instant, usize)>,
+}
+
+impl Reports {
+    /// Starts timing a transfer of `total` packages, 0 when unknown
+    pub(crate) fn new(reporter: PackagesReporter, total: usize) -> Self {
+        let skip = match &reporter.speed {
+            Some(speed) => speed.apply_multiplier(total).max(1),
+            None => 1,
+        };
+
+        Self {
+            reporter,
+            skip,
+            started: Instant::now(),
+            start_bytes: 0,
+            last: None,
+        }
+    }
+
+    /// Leaves the bytes persisted before a resumed transfer out of the throughput
+    pub(crate) fn resume(&mut self, bytes: usize) {
+        self.start_bytes = bytes;
+    }
+
+    /// Reports the counters of the transfer unless the report is skipped, `last` is never skipped
+    pub(crate) fn report(&mut self, mut report: PackagesReport, last: bool) {
+        let Some(callback) = &mut self.reporter.callback else {
+            return;
+        };
+
+        let now = Instant::now();
+
+        if !last {
+            let throttled = match (self.reporter.interval, self.last) {
+                (Some(interval), Some((reported, _))) => now - reported < interval,
+                _ => false,
+            };
+
+            if throttled || !report.sent.is_multiple_of(self.skip) {
+                return;
+            }
+        }
+
+        let (since, bytes_before) = self.last.unwrap_or((self.started, self.start_bytes));
+
+        report.elapsed = now - self.started;
+        report.throughput = per_second(report.bytes_sent.saturating_sub(bytes_before), now - since);
+        report.average_throughput = per_second(
+            report.bytes_sent.saturating_sub(self.start_bytes),
+            report.elapsed,
+        );
+        report.eta = if report.total != 0 && report.average_throughput > 0.0 {
+            let left = report.total_bytes.saturating_sub(report.bytes_sent);
+            Some(Duration::from_secs_f64(
+                left as f64 / report.average_throughput,
+            ))
+        } else {
+            None
+        };
+
+        self.last = Some((now, report.bytes_sent));
+
+        callback(report);
+    }
+}
+
+fn per_second(bytes: usize, duration: Duration) -> f64 {
+    if duration.is_zero() {
+        0.0
+    } else {
+        bytes as f64 / duration.as_secs_f64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::package::report::{PackageReportSpeed, PackagesReport, PackagesReporter, Reports};
+
+    #[test]
+    fn reports_are_timed() {
+        let reports = Arc::new(Mutex::new(vec![]));
+        let sink = reports.clone();
+
+        let mut reporter = PackagesReporter::new(move |report| sink.lock().unwrap().push(report));
+        reporter.set_report_speed(PackageReportSpeed::FASTEST);
+
+        let mut tracker = Reports::new(reporter, 4);
+
+        for sent in 1..=4 {
+            std::thread::sleep(Duration::from_millis(5));
+            tracker.report(
+                PackagesReport {
+                    sent,
+                    total: 4,
+                    bytes_sent: sent * 1000,
+                    total_bytes: 4000,
+                    ..PackagesReport::default()
+                },
+                sent == 4,
+            );
+        }
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 4);
+
+        assert!(reports[0].throughput > 0.0 && reports[0].average_throughput > 0.0);
+        assert!(reports[0].eta.unwrap() > Duration::ZERO);
+        assert!(reports[3].elapsed >= Duration::from_millis(20));
+        assert_eq!(reports[3].eta, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn reports_are_throttled() {
+        let reports = Arc::new(Mutex::new(vec![]));
+        let sink = reports.clone();
+
+        let mut reporter = PackagesReporter::new(move |report| sink.lock().unwrap().push(report));
+        reporter.set_report_speed(PackageReportSpeed::FASTEST);
+        reporter.set_report_interval(Duration::from_secs(60));
+
+        // Unknown total as for receivers
+        let mut tracker = Reports::new(reporter, 0);
+
+        for sent in 1..=100 {
+            let report = PackagesReport {
+                sent,
+                bytes_sent: sent * 10,
+                ..PackagesReport::default()
+            };
+            tracker.report(report, sent == 100);
+        }
+
+        let reports = reports.lock().unwrap();
+
+        // The first report and the last one which is never skipped
+        assert_eq!(
+            reports.iter().map(|report| report.sent).collect::<Vec<_>>(),
+            [1, 100]
+        );
+        assert!(reports.iter().all(|report| report.eta.is_none()));
+    }
+}
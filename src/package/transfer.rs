@@ -0,0 +1,179 @@
+use std::{collections::HashMap, fs, io::Read, path::Path};
+
+use uuid::Uuid;
+
+use crate::{
+    bufferable::Bufferable,
+    codec,
+    error::{MtpError, Result},
+};
+
+/// How much of a resumable transfer the receiver has persisted,
+/// packages `1..=item` holding `bytes` bytes of data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TransferProgress {
+    pub item: usize,
+    pub bytes: usize,
+}
+
+impl Bufferable for TransferProgress {
+    /// Buffer model
+    /// - First 4 bytes (0, 3) last contiguous item number
+    /// - Next 8 bytes (4, 11) bytes persisted
+    fn to_buffer(self) -> Result<Vec<u8>> {
+        let mut buffer = codec::encode::<4>(self.item)?.to_vec();
+        buffer.extend_from_slice(&codec::encode::<8>(self.bytes)?);
+
+        Ok(buffer)
+    }
+
+    fn from_stream<R: Read>(stream: &mut R) -> Result<Self> {
+        Ok(Self {
+            item: codec::read::<R, 4>(stream)?,
+            bytes: codec::read::<R, 8>(stream)?,
+        })
+    }
+}
+
+/// Progress of the resumable transfers received, kept by the receiver
+/// so an interrupted transfer restarts from where it stopped.
+///
+/// The progress is recorded once the data has been written to the sink and flushed, keep
+/// the sink truncated to [`TransferProgress::bytes`] when resuming.
+#[derive(Debug, Clone, Default)]
+pub struct TransferLog {
+    transfers: HashMap<Uuid, TransferProgress>,
+}
+
+impl TransferLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Progress of the transfer, nothing persisted for unknown transfers
+    pub fn get(&self, transfer_id: &Uuid) -> TransferProgress {
+        self.transfers.get(transfer_id).copied().unwrap_or_default()
+    }
+
+    pub fn record(&mut self, transfer_id: Uuid, progress: TransferProgress) {
+        self.transfers.insert(transfer_id, progress);
+    }
+
+    /// Forgets a transfer, finished transfers are kept until removed
+    /// so a sender retrying a finished transfer does not start over
+    pub fn remove(&mut self, transfer_id: &Uuid) -> Option<TransferProgress> {
+        self.transfers.remove(transfer_id)
+    }
+
+    /// Loads a transfer log file, one transfer per line, empty
+    /// lines and lines starting with `#` are skipped
+    /// ```text
+    /// # transfer id                        item bytes
+    /// 67e55044-10b1-426f-9247-bb680e5fe0c8 1024 4193280
+    /// ```
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(MtpError::Io)?;
+        let mut log = Self::new();
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+
+            let (Some(transfer_id), Some(item), Some(bytes)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(MtpError::MalformedHeader(
+                    "transfer log line must be `<transfer id> <item> <bytes>`",
+                ));
+            };
+
+            let transfer_id = Uuid::parse_str(transfer_id)
+                .map_err(|_| MtpError::MalformedHeader("transfer id is not a uuid"))?;
+            let progress = TransferProgress {
+                item: item
+                    .parse()
+                    .map_err(|_| MtpError::MalformedHeader("transfer item is not a number"))?,
+                bytes: bytes
+                    .parse()
+                    .map_err(|_| MtpError::MalformedHeader("transfer bytes is not a number"))?,
+            };
+
+            log.record(transfer_id, progress);
+        }
+
+        Ok(log)
+    }
+
+    /// Writes the transfers in the format read by [`TransferLog::load`]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut content = String::new();
+
+        for (transfer_id, progress) in &self.transfers {
+            content.push_str(&format!(
+                "{} {} {}\n",
+                transfer_id, progress.item, progress.bytes
+            ));
+        }
+
+        fs::write(path, content).map_err(MtpError::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use uuid::Uuid;
+
+    use crate::{
+        bufferable::Bufferable,
+        package::transfer::{TransferLog, TransferProgress},
+    };
+
+    #[test]
+    fn transfer_progress_buffer() {
+        let progress = TransferProgress {
+            item: 70_000,
+            bytes: 4_586_471_424,
+        };
+
+        let buffer = progress.to_buffer().unwrap();
+
+        assert_eq!(buffer.len(), 12);
+        assert_eq!(
+            TransferProgress::from_stream(&mut Cursor::new(buffer)).unwrap(),
+            progress
+        );
+    }
+
+    #[test]
+    fn transfer_log_file() {
+        let path = std::env::temp_dir().join(format!("mtp-transfers-{}", Uuid::new_v4()));
+        let transfer_id = Uuid::new_v4();
+
+        let mut log = TransferLog::new();
+        log.record(
+            transfer_id,
+            TransferProgress {
+                item: 3,
+                bytes: 12285,
+            },
+        );
+        log.save(&path).unwrap();
+
+        let loaded = TransferLog::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            loaded.get(&transfer_id),
+            TransferProgress {
+                item: 3,
+                bytes: 12285
+            }
+        );
+        assert_eq!(loaded.get(&Uuid::new_v4()), TransferProgress::default());
+    }
+}
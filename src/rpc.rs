@@ -0,0 +1,248 @@
+use std::{io::Write, sync::Mutex};
+
+use crate::{
+    error::{MtpError, Result},
+    multiplex::{
+        check_multiplexed, lock, read_frame, read_message, write_message, Channel, Multiplexer,
+    },
+    package::{max_sealed_length, packages::Packages},
+    stream::{check_packages, Stream},
+};
+
+/// # Request / response procedure
+/// A request is a message sent on a channel of a [`Multiplexer`] picked at random,
+/// the channel identifier of the package meta header being the correlation ID.
+/// The response is sent back on the same channel, requests and responses larger than
+/// a package are split in packages like [`Packages`] are.
+impl Stream {
+    /// Sends the payload as a request to a party serving with [`Stream::serve`]
+    /// and waits for its response.
+    ///
+    /// Only one request is in flight at a time, see [`Multiplexer::request`]
+    /// to send requests from several threads at once
+    /// ```ignore
+    /// let mut stream = Stream::connect("127.0.0.1:3400")?;
+    /// let playlist = stream.request(b"playlist".to_vec())?;
+    /// ```
+    pub fn request(&mut self, payload: Vec<u8>) -> Result<Vec<u8>> {
+        let session_key = self.session_key()?.clone();
+        check_multiplexed(&self.capabilities)?;
+
+        let mut packages = Packages::new(vec![]);
+        check_packages(&self.capabilities, &packages)?;
+
+        let correlation_id = rand::random::<u32>();
+        let tcp_stream = &mut self.tcp_stream;
+
+        write_message(
+            &mut packages,
+            &session_key,
+            correlation_id,
+            payload.as_slice(),
+            Some(payload.len()),
+            self.capabilities.max_package_size,
+            |buffer| Ok(tcp_stream.write_all(buffer)?),
+        )?;
+
+        let max_package_size = self.capabilities.max_package_size;
+        let max_length = max_sealed_length(max_package_size);
+
+        let mut response = vec![];
+        read_message(&session_key, max_package_size, &mut response, || {
+            let frame = read_frame(tcp_stream, max_length)?;
+
+            if frame.channel() != correlation_id {
+                return Err(MtpError::MalformedHeader(
+                    "response does not match the request",
+                ));
+            }
+
+            Ok(frame)
+        })?;
+
+        Ok(response)
+    }
+
+    /// Answers every request with the response returned by the handler until
+    /// the other party closes the connection, returns the errors of the requests
+    /// which could not be answered, see [`Multiplexer::serve`]
+    /// ```ignore
+    /// let listener = MtpListener::bind("127.0.0.1:3400")?;
+    /// let (stream, _) = listener.accept()?;
+    ///
+    /// stream.serve(|request| match request.as_slice() {
+    ///     b"playlist" => playlist.clone(),
+    ///     _ => vec![],
+    /// })?;
+    /// ```
+    pub fn serve<F: Fn(Vec<u8>) -> Vec<u8> + Sync>(self, handler: F) -> Result<Vec<MtpError>> {
+        // Accepting from the start, requests are never dropped while waiting to be accepted
+        Multiplexer::accepting(self)?.serve(handler)
+    }
+}
+
+impl Multiplexer {
+    /// Same as [`Stream::request`], requests sent from several threads are in flight at once
+    pub fn request(&self, payload: Vec<u8>) -> Result<Vec<u8>> {
+        let mut channel = self.request_channel()?;
+
+        channel.send(payload)?;
+        channel.receive()
+    }
+
+    /// Same as [`Stream::serve`], every request is handled on its own thread
+    /// so requests in flight at once are answered at once.
+    ///
+    /// Requests received before serving are dropped once they are over 64 packages,
+    /// they are returned as [`MtpError::MessagesDropped`]
+    pub fn serve<F: Fn(Vec<u8>) -> Vec<u8> + Sync>(&self, handler: F) -> Result<Vec<MtpError>> {
+        let handler = &handler;
+        let failures = Mutex::new(vec![]);
+
+        std::thread::scope(|scope| loop {
+            let mut channel = match self.accept() {
+                Ok(channel) => channel,
+                // Every request thread is joined once the scope ends
+                Err(MtpError::PeerAborted) => break Ok(()),
+                Err(err @ MtpError::MessagesDropped(_)) => {
+                    lock(&failures).push(err);
+                    continue;
+                }
+                Err(err) => break Err(err),
+            };
+
+            let failures = &failures;
+
+            // A request may fail on its own (a package which does not decrypt, an oversized
+            // response...) while the connection and the other requests carry on
+            scope.spawn(move || {
+                let answered = channel
+                    .receive()
+                    .and_then(|request| channel.send(handler(request)));
+
+                if let Err(err) = answered {
+                    lock(failures).push(err);
+                }
+            });
+        })?;
+
+        Ok(failures
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+
+    /// Opens a channel with a random identifier not in use
+    fn request_channel(&self) -> Result<Channel> {
+        loop {
+            match self.channel(rand::random()) {
+                Err(MtpError::ChannelInUse(_)) => continue,
+                channel => return channel,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::{
+        bufferable::Bufferable,
+        capabilities::Capabilities,
+        error::MtpError,
+        package::{
+            meta::{PackageMeta, PackageType},
+            packages::footer,
+            Package,
+        },
+    };
+
+    fn reverse(mut request: Vec<u8>) -> Vec<u8> {
+        request.reverse();
+        request
+    }
+
+    #[test]
+    fn request_response() {
+        let (server, mut client) = crate::tests::handshaken_pair(Capabilities::default());
+
+        let server = std::thread::spawn(move || server.serve(reverse));
+
+        assert_eq!(client.request(b"ping".to_vec()).unwrap(), b"gnip");
+
+        // Larger than a package both ways
+        let payload = (0..100_000).map(|i| (i % 256) as u8).collect::<Vec<u8>>();
+        assert_eq!(client.request(payload.clone()).unwrap(), reverse(payload));
+
+        drop(client);
+        assert!(server.join().unwrap().unwrap().is_empty());
+    }
+
+    #[test]
+    fn requests_larger_than_channel_queue() {
+        let (server, mut client) = crate::tests::handshaken_pair(Capabilities::default());
+
+        let server = std::thread::spawn(move || server.serve(reverse));
+
+        // Hundreds of packages, far more than a channel keeps before it is accepted
+        let payload = (0..1_000_000).map(|i| (i % 253) as u8).collect::<Vec<u8>>();
+
+        for _ in 0..3 {
+            assert_eq!(
+                client.request(payload.clone()).unwrap(),
+                reverse(payload.clone())
+            );
+        }
+
+        drop(client);
+        assert!(server.join().unwrap().unwrap().is_empty());
+    }
+
+    #[test]
+    fn concurrent_requests() {
+        let (server, client) = crate::tests::handshaken_pair(Capabilities::default());
+
+        let server = std::thread::spawn(move || server.serve(reverse));
+        let client = client.multiplex().unwrap();
+
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let client = &client;
+
+                scope.spawn(move || {
+                    let payload = (0..10_000 * i)
+                        .map(|j| (j % 251) as u8)
+                        .collect::<Vec<u8>>();
+                    assert_eq!(client.request(payload.clone()).unwrap(), reverse(payload));
+                });
+            }
+        });
+
+        client.shutdown().unwrap();
+        assert!(server.join().unwrap().unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_requests_are_returned() {
+        let (server, mut client) = crate::tests::handshaken_pair(Capabilities::default());
+
+        let server = std::thread::spawn(move || server.serve(reverse));
+
+        // A request whose package is not encrypted can not be answered
+        let meta = PackageMeta {
+            channel: 5,
+            ..PackageMeta::new(1, PackageType::PACKAGES)
+        };
+        let mut buffer = Package::new(b"plain".to_vec(), meta).to_buffer().unwrap();
+        buffer.push(footer::LAST);
+        client.tcp_stream.write_all(&buffer).unwrap();
+
+        // The connection carries on
+        assert_eq!(client.request(b"ping".to_vec()).unwrap(), b"gnip");
+
+        drop(client);
+        let failures = server.join().unwrap().unwrap();
+
+        assert!(matches!(failures.as_slice(), [MtpError::NotEncrypted]));
+    }
+}
@@ -1,6 +1,8 @@
 use std::{
+    fmt,
     io::{Read, Write},
-    net::TcpStream,
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
 };
 
 use openssl::{
@@ -9,8 +11,15 @@ use openssl::{
 };
 
 use crate::bufferable::Bufferable;
+use crate::capabilities::Capabilities;
+use crate::codec;
+use crate::crypto::{decrypt, encrypt, generate_key_share, sign, verify, SessionKey};
+use crate::error::{MtpError, Result};
+use crate::identity::Identity;
+use crate::stream::Stream;
+use crate::trust::TrustStore;
 // use crate::utils::{recompute_u16_from_u8_group, u16_to_u8_group, u8_group_to_vec};
-use crate::utils::macros::{u8_bytes_to_usize, usize_to_u8_bytes};
+use crate::utils::{read_array, read_length_prefixed};
 
 /// A Shake is required to establish a mutually secured encrypted connection
 /// with the client and server.
@@ -18,20 +27,24 @@ use crate::utils::macros::{u8_bytes_to_usize, usize_to_u8_bytes};
 /// A shake may include a bit of data of the client and also include the public key of the client.
 /// If a HandShake is not initialized between both parties the communication will not be secure.  
 ///
+/// The shake starts with the [`Capabilities`] of the party so both parties
+/// can agree on a protocol version before reading anything else.
+///
 /// The data can only be a maximum of
 #[derive(Debug, Clone)]
 pub struct Shake {
+    pub capabilities: Capabilities,
     pub data: Vec<u8>,
     pub public_key: PKey<Public>,
 }
 
 impl Bufferable for Shake {
-    fn to_buffer(mut self) -> Vec<u8> {
-        let mut buffer = vec![];
-        let mut public_key_bytes = self.public_key.public_key_to_pem().unwrap();
+    fn to_buffer(mut self) -> Result<Vec<u8>> {
+        let mut buffer = self.capabilities.to_buffer()?;
+        let mut public_key_bytes = self.public_key.public_key_to_pem()?;
 
-        let mut public_key_bytes_length = usize_to_u8_bytes!(public_key_bytes.len(); 3).to_vec();
-        let mut data_length = usize_to_u8_bytes!(self.data.len(); 3).to_vec();
+        let mut public_key_bytes_length = codec::encode::<3>(public_key_bytes.len())?.to_vec();
+        let mut data_length = codec::encode::<3>(self.data.len())?.to_vec();
 
         buffer.append(&mut public_key_bytes_length);
         buffer.append(&mut public_key_bytes);
@@ -39,46 +52,115 @@ impl Bufferable for Shake {
         buffer.append(&mut data_length);
         buffer.append(&mut self.data);
 
-        buffer
+        Ok(buffer)
     }
 
-    fn from_stream(stream: &mut TcpStream) -> Self {
-        let public_key_pem = Self::read_public_key(stream);
-        let public_key =
-            PKey::public_key_from_pem(&public_key_pem).expect("expected valid public key pem");
-        let data = Self::read_data(stream);
-
-        Self { data, public_key }
+    fn from_stream<R: Read>(stream: &mut R) -> Result<Self> {
+        let capabilities = Capabilities::from_stream(stream)?;
+        let public_key_pem = Self::read_public_key(stream)?;
+        let public_key = PKey::public_key_from_pem(&public_key_pem).map_err(MtpError::BadPem)?;
+        let data = Self::read_data(stream)?;
+
+        Ok(Self {
+            capabilities,
+            data,
+            public_key,
+        })
     }
 }
 
 impl Shake {
-    fn read_public_key(stream: &mut TcpStream) -> Vec<u8> {
-        let mut public_key_size_u8_group = [0; 3];
-        stream
-            .read(&mut public_key_size_u8_group)
-            .expect("expected to read public key size u8 group");
+    fn read_public_key<R: Read>(stream: &mut R) -> Result<Vec<u8>> {
+        read_length_prefixed(stream)
+    }
 
-        let mut public_key = vec![0; u8_bytes_to_usize!(public_key_size_u8_group)];
+    fn read_data<R: Read>(stream: &mut R) -> Result<Vec<u8>> {
+        read_length_prefixed(stream)
+    }
+}
 
-        stream
-            .read(&mut public_key)
-            .expect("expected to read public key");
-        public_key
+/// Sent by both parties once the shakes have been exchanged, holds a
+/// random key share encrypted with the other party's public key.
+///
+/// Both key shares are combined into the [`SessionKey`] used for bulk encryption.
+#[derive(Debug, Clone)]
+pub struct KeyShare {
+    pub wrapped_share: Vec<u8>,
+}
+
+impl Bufferable for KeyShare {
+    fn to_buffer(mut self) -> Result<Vec<u8>> {
+        let mut buffer = codec::encode::<3>(self.wrapped_share.len())?.to_vec();
+        buffer.append(&mut self.wrapped_share);
+
+        Ok(buffer)
     }
 
-    fn read_data(stream: &mut TcpStream) -> Vec<u8> {
-        let mut data_size_u8_group = [0; 3];
+    fn from_stream<R: Read>(stream: &mut R) -> Result<Self> {
+        let wrapped_share = read_length_prefixed(stream)?;
 
-        stream
-            .read(&mut data_size_u8_group)
-            .expect("expected to read data size u8 group");
+        Ok(Self { wrapped_share })
+    }
+}
 
-        let mut data = vec![0; u8_bytes_to_usize!(data_size_u8_group)];
+/// Random bytes sent by both parties after the key shares,
+/// the other party has to answer with a [`ChallengeResponse`]
+#[derive(Debug, Clone)]
+pub struct Challenge {
+    pub nonce: [u8; 32],
+}
+
+impl Challenge {
+    pub fn new() -> Self {
+        Self {
+            nonce: generate_key_share(),
+        }
+    }
 
-        stream.read(&mut data).expect("expected to read data");
+    /// Bytes signed by the other party, prefixed so a signature made
+    /// for the handshake cannot be reused for anything else
+    fn signed_data(&self) -> Vec<u8> {
+        [b"mtp-handshake".as_slice(), &self.nonce].concat()
+    }
+}
 
-        data
+impl Default for Challenge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bufferable for Challenge {
+    fn to_buffer(self) -> Result<Vec<u8>> {
+        Ok(self.nonce.to_vec())
+    }
+
+    fn from_stream<R: Read>(stream: &mut R) -> Result<Self> {
+        Ok(Self {
+            nonce: read_array(stream)?,
+        })
+    }
+}
+
+/// Signature of the other party's [`Challenge`] made with our private key,
+/// proves we own the public key sent in our [`Shake`]
+#[derive(Debug, Clone)]
+pub struct ChallengeResponse {
+    pub signature: Vec<u8>,
+}
+
+impl Bufferable for ChallengeResponse {
+    fn to_buffer(mut self) -> Result<Vec<u8>> {
+        let mut buffer = codec::encode::<3>(self.signature.len())?.to_vec();
+        buffer.append(&mut self.signature);
+
+        Ok(buffer)
+    }
+
+    fn from_stream<R: Read>(stream: &mut R) -> Result<Self> {
+        let signature = read_length_prefixed(stream)?;
+
+        Ok(Self { signature })
     }
 }
 
@@ -86,28 +168,277 @@ impl Shake {
 /// be recognized and public keys will be store for further use
 #[derive(Debug)]
 pub enum Handshake {
-    /// A secure handshake which includes its corresponding Private key
-    /// and the clients public key for secure communications  
-    SHAKEN(Shake, Rsa<Private>),
+    /// A secure handshake which includes its corresponding Private key,
+    /// the clients public key and the session key agreed by both parties
+    /// for secure communications
+    SHAKEN(Shake, Rsa<Private>, SessionKey),
 
     /// A unsecure method of communication which makes
     /// use of raw data transfer with no encryption
     UNSHAKEN,
 }
 
-/// Quick method to perform a simple handshake
-pub fn perform_handshake(stream: &mut TcpStream) -> Handshake {
-    let key = Rsa::generate(2048).unwrap();
-    let public_key = PKey::public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap();
+impl Handshake {
+    /// Shake sent by the other party, `None` when `UNSHAKEN`
+    pub fn peer_shake(&self) -> Option<&Shake> {
+        match self {
+            Self::SHAKEN(shake, _, _) => Some(shake),
+            Self::UNSHAKEN => None,
+        }
+    }
 
-    let shake = Shake {
-        data: String::from("awa").as_bytes().to_vec(),
-        public_key,
-    };
+    /// Payload the other party attached to its shake, `None` when `UNSHAKEN`
+    pub fn peer_payload(&self) -> Option<&[u8]> {
+        self.peer_shake().map(|shake| shake.data.as_slice())
+    }
+}
 
-    stream.write(&shake.to_buffer()).unwrap();
+/// Decides whether the payload sent by the other party is accepted, closures
+/// can capture state such as the token expected from the other party
+pub type PayloadValidator = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// Configures the handshake performed with the other party
+/// ```ignore
+/// let stream = HandshakeBuilder::new()
+///     .payload(b"music-client/1.0".to_vec())
+///     .validate(|payload| payload.starts_with(b"music-server/"))
+///     .connect("127.0.0.1:3400")?;
+/// ```
+#[derive(Clone, Default)]
+pub struct HandshakeBuilder {
+    capabilities: Capabilities,
+    identity: Option<Identity>,
+    trust_store: TrustStore,
+    payload: Vec<u8>,
+    validator: Option<PayloadValidator>,
+}
 
-    Handshake::SHAKEN(Shake::from_stream(stream), key)
+impl fmt::Debug for HandshakeBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandshakeBuilder")
+            .field("capabilities", &self.capabilities)
+            .field("identity", &self.identity)
+            .field("trust_store", &self.trust_store)
+            .field("payload", &self.payload)
+            .field("validating", &self.validator.is_some())
+            .finish()
+    }
+}
+
+impl HandshakeBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Capabilities offered to the other party, everything this crate supports by default
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Identity to perform the handshake as, a new one
+    /// is generated for every handshake when not set
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    pub(crate) fn has_identity(&self) -> bool {
+        self.identity.is_some()
+    }
+
+    /// Public keys accepted from the other party, any key by default
+    pub fn trust_store(mut self, trust_store: TrustStore) -> Self {
+        self.trust_store = trust_store;
+        self
+    }
+
+    /// Data sent to the other party in our shake (client name, app version, token...),
+    /// read on the other side through [`Handshake::peer_payload`]
+    pub fn payload(mut self, payload: Vec<u8>) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// Rejects the connection with [`MtpError::PayloadRejected`]
+    /// when the validator returns false for the other party's payload
+    pub fn validate<F: Fn(&[u8]) -> bool + Send + Sync + 'static>(
+        mut self,
+        validator: F,
+    ) -> Self {
+        self.validator = Some(Arc::new(validator));
+        self
+    }
+
+    /// Performs the handshake, fails with [`MtpError::UntrustedPeer`] when the other
+    /// party's public key is not in the trust store, with [`MtpError::PayloadRejected`]
+    /// when the validator rejects its payload and with [`MtpError::BadSignature`]
+    /// when it cannot prove it owns the matching private key
+    pub fn perform<S: Read + Write>(&self, stream: &mut S) -> Result<Handshake> {
+        Ok(self.negotiate(stream)?.0)
+    }
+
+    /// Performs the handshake and returns the capabilities both parties share
+    pub(crate) fn negotiate<S: Read + Write>(
+        &self,
+        stream: &mut S,
+    ) -> Result<(Handshake, Capabilities)> {
+        let (mut state, shake) = HandshakeState::start(self)?;
+
+        stream.write_all(&shake.to_buffer()?)?;
+        let key_share = state.receive_shake(Shake::from_stream(stream)?)?;
+
+        stream.write_all(&key_share.to_buffer()?)?;
+        let challenge = state.receive_key_share(KeyShare::from_stream(stream)?)?;
+
+        stream.write_all(&challenge.to_buffer()?)?;
+        let response = state.receive_challenge(Challenge::from_stream(stream)?)?;
+
+        stream.write_all(&response.to_buffer()?)?;
+        state.finish(ChallengeResponse::from_stream(stream)?)
+    }
+
+    /// Performs the handshake over an already connected tcp stream,
+    /// the stream keeps the capabilities both parties share
+    pub fn connect_stream(&self, mut tcp_stream: TcpStream) -> Result<Stream> {
+        let (handshaken, capabilities) = self.negotiate(&mut tcp_stream)?;
+
+        Ok(Stream {
+            tcp_stream,
+            handshaken,
+            capabilities,
+        })
+    }
+
+    /// Connects to the address and performs the handshake
+    pub fn connect<T: ToSocketAddrs>(&self, addr: T) -> Result<Stream> {
+        let tcp_stream = TcpStream::connect(addr).map_err(MtpError::Io)?;
+
+        self.connect_stream(tcp_stream)
+    }
+}
+
+/// Progress of a handshake independent of the stream it is performed over.
+///
+/// Every step takes the message received from the other party and returns the
+/// next message to send, so blocking and async streams share the same protocol
+/// - A -> B [`Shake`], B -> A [`Shake`]
+/// - A -> B [`KeyShare`], B -> A [`KeyShare`]
+/// - A -> B [`Challenge`], B -> A [`Challenge`]
+/// - A -> B [`ChallengeResponse`], B -> A [`ChallengeResponse`]
+pub(crate) struct HandshakeState<'a> {
+    builder: &'a HandshakeBuilder,
+    key: Rsa<Private>,
+    key_share: [u8; 32],
+    challenge: Challenge,
+    peer: Option<(Shake, Capabilities)>,
+    session_key: Option<SessionKey>,
+}
+
+impl<'a> HandshakeState<'a> {
+    /// Starts the handshake, returns our shake
+    pub(crate) fn start(builder: &'a HandshakeBuilder) -> Result<(Self, Shake)> {
+        let identity = match &builder.identity {
+            Some(identity) => identity.clone(),
+            None => Identity::generate()?,
+        };
+
+        let shake = Shake {
+            capabilities: builder.capabilities,
+            data: builder.payload.clone(),
+            public_key: identity.public_key()?,
+        };
+
+        let state = Self {
+            builder,
+            key: identity.private_key().clone(),
+            key_share: generate_key_share(),
+            challenge: Challenge::new(),
+            peer: None,
+            session_key: None,
+        };
+
+        Ok((state, shake))
+    }
+
+    /// Checks the other party's shake, returns our key share
+    pub(crate) fn receive_shake(&mut self, peer_shake: Shake) -> Result<KeyShare> {
+        let capabilities = self.builder.capabilities.intersect(&peer_shake.capabilities)?;
+        self.builder.trust_store.verify(&peer_shake.public_key)?;
+
+        if let Some(validator) = &self.builder.validator {
+            if !validator(&peer_shake.data) {
+                return Err(MtpError::PayloadRejected);
+            }
+        }
+
+        let key_share = KeyShare {
+            wrapped_share: encrypt(&peer_shake.public_key, &self.key_share)?,
+        };
+
+        self.peer = Some((peer_shake, capabilities));
+
+        Ok(key_share)
+    }
+
+    /// Derives the session key, returns our challenge
+    pub(crate) fn receive_key_share(&mut self, peer_key_share: KeyShare) -> Result<Challenge> {
+        let peer_key_share = decrypt(&self.key, &peer_key_share.wrapped_share)?;
+        self.session_key = Some(SessionKey::derive(&self.key_share, &peer_key_share));
+
+        Ok(self.challenge.clone())
+    }
+
+    /// Signs the other party's challenge
+    pub(crate) fn receive_challenge(&self, peer_challenge: Challenge) -> Result<ChallengeResponse> {
+        Ok(ChallengeResponse {
+            signature: sign(&self.key, &peer_challenge.signed_data())?,
+        })
+    }
+
+    /// Checks the other party signed our challenge with the private
+    /// key of the public key in its shake
+    pub(crate) fn finish(
+        self,
+        peer_response: ChallengeResponse,
+    ) -> Result<(Handshake, Capabilities)> {
+        let (Some((peer_shake, capabilities)), Some(session_key)) = (self.peer, self.session_key)
+        else {
+            return Err(MtpError::MalformedHeader(
+                "handshake finished before every message was received",
+            ));
+        };
+
+        if !verify(
+            &peer_shake.public_key,
+            &self.challenge.signed_data(),
+            &peer_response.signature,
+        )? {
+            return Err(MtpError::BadSignature);
+        }
+
+        Ok((Handshake::SHAKEN(peer_shake, self.key, session_key), capabilities))
+    }
+}
+
+/// Quick method to perform a simple handshake with a freshly
+/// generated identity, any public key is accepted
+pub fn perform_handshake<S: Read + Write>(stream: &mut S) -> Result<Handshake> {
+    HandshakeBuilder::new().perform(stream)
+}
+
+/// Performs a handshake as the given identity which fails with [`MtpError::UntrustedPeer`]
+/// when the other party's public key is not in the trust store and with
+/// [`MtpError::BadSignature`] when it cannot prove it owns the matching private key
+pub fn perform_trusted_handshake<S: Read + Write>(
+    stream: &mut S,
+    identity: &Identity,
+    trust_store: &TrustStore,
+) -> Result<Handshake> {
+    HandshakeBuilder::new()
+        .identity(identity.clone())
+        .trust_store(trust_store.clone())
+        .perform(stream)
 }
 
 #[cfg(test)]
@@ -117,7 +448,12 @@ mod tests {
 
     use crate::{
         bufferable::Bufferable,
-        shake::{Handshake, Shake},
+        capabilities::{capability, Capabilities},
+        crypto::{generate_key_share, SessionKey},
+        error::MtpError,
+        identity::Identity,
+        shake::{perform_handshake, perform_trusted_handshake, Handshake, HandshakeBuilder, Shake},
+        trust::TrustStore,
     };
 
     #[test]
@@ -137,29 +473,31 @@ mod tests {
             PKey::public_key_from_pem(&server_key.public_key_to_pem().unwrap()).unwrap();
 
         let client_shake = Shake {
+            capabilities: Capabilities::default(),
             data: String::from(client_data).as_bytes().to_vec(),
             public_key: client_public_key.clone(),
         };
 
         let server_shake = Shake {
+            capabilities: Capabilities::default(),
             data: String::from(server_data).as_bytes().to_vec(),
             public_key: server_public_key.clone(),
         };
 
         client_stream
             .tcp_stream
-            .write(&client_shake.clone().to_buffer())
+            .write_all(&client_shake.clone().to_buffer().unwrap())
             .unwrap();
 
         server_stream
             .tcp_stream
-            .write(&server_shake.clone().to_buffer())
+            .write_all(&server_shake.clone().to_buffer().unwrap())
             .unwrap();
 
         // Data transmitted from client
-        let shake_from_client = Shake::from_stream(&mut server_stream.tcp_stream);
+        let shake_from_client = Shake::from_stream(&mut server_stream.tcp_stream).unwrap();
         // Data transmitted from server
-        let shake_from_server = Shake::from_stream(&mut client_stream.tcp_stream);
+        let shake_from_server = Shake::from_stream(&mut client_stream.tcp_stream).unwrap();
 
         let shake_from_client_data_string =
             String::from_utf8_lossy(&shake_from_client.data).to_string();
@@ -169,8 +507,11 @@ mod tests {
         let shake_from_client_public_key = shake_from_client.public_key.clone();
         let shake_from_server_public_key = shake_from_server.public_key.clone();
 
-        client_stream.handshaken = Handshake::SHAKEN(shake_from_server, client_key);
-        server_stream.handshaken = Handshake::SHAKEN(shake_from_client, server_key);
+        let session_key = SessionKey::derive(&generate_key_share(), &generate_key_share());
+
+        client_stream.handshaken =
+            Handshake::SHAKEN(shake_from_server, client_key, session_key.clone());
+        server_stream.handshaken = Handshake::SHAKEN(shake_from_client, server_key, session_key);
 
         /*
             checks whether the server and client received the
@@ -193,4 +534,164 @@ mod tests {
             server_public_key.public_key_to_pem().unwrap()
         );
     }
+
+    #[test]
+    fn perform_handshake_agrees_on_session_key() {
+        let (mut server_stream, mut client_stream) =
+            crate::tests::stablish_server_client_connection().split();
+
+        let server = std::thread::spawn(move || perform_handshake(&mut server_stream.tcp_stream));
+        let client_handshake = perform_handshake(&mut client_stream.tcp_stream).unwrap();
+        let server_handshake = server.join().unwrap().unwrap();
+
+        match (client_handshake, server_handshake) {
+            (
+                Handshake::SHAKEN(_, _, client_session_key),
+                Handshake::SHAKEN(_, _, server_session_key),
+            ) => assert_eq!(client_session_key, server_session_key),
+            _ => panic!("expected both streams to be shaken"),
+        }
+    }
+
+    #[test]
+    fn bad_public_key_pem() {
+        let (mut server_stream, mut client_stream) =
+            crate::tests::stablish_server_client_connection().split();
+
+        let pem = b"not a pem";
+        let mut buffer = Capabilities::default().to_buffer().unwrap();
+        buffer.extend_from_slice(&[0, 0, pem.len() as u8]);
+        buffer.extend_from_slice(pem);
+        buffer.extend_from_slice(&[0, 0, 0]);
+
+        client_stream.tcp_stream.write_all(&buffer).unwrap();
+
+        assert!(matches!(
+            Shake::from_stream(&mut server_stream.tcp_stream),
+            Err(MtpError::BadPem(_))
+        ));
+    }
+
+    #[test]
+    fn untrusted_peer_is_rejected() {
+        let (mut server_stream, mut client_stream) =
+            crate::tests::stablish_server_client_connection().split();
+
+        // The server's key is never pinned by the client
+        let server = std::thread::spawn(move || perform_handshake(&mut server_stream.tcp_stream));
+
+        let trust_store = TrustStore::pinned([]);
+        let client_handshake = perform_trusted_handshake(
+            &mut client_stream.tcp_stream,
+            &Identity::generate().unwrap(),
+            &trust_store,
+        );
+        drop(client_stream);
+
+        assert!(matches!(client_handshake, Err(MtpError::UntrustedPeer(_))));
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn pinned_identities_are_accepted() {
+        let (mut server_stream, mut client_stream) =
+            crate::tests::stablish_server_client_connection().split();
+
+        let server_identity = Identity::generate().unwrap();
+        let client_identity = Identity::generate().unwrap();
+
+        let server_trust_store = TrustStore::pinned([client_identity.fingerprint().unwrap()]);
+        let client_trust_store = TrustStore::pinned([server_identity.fingerprint().unwrap()]);
+
+        let server = std::thread::spawn(move || {
+            perform_trusted_handshake(
+                &mut server_stream.tcp_stream,
+                &server_identity,
+                &server_trust_store,
+            )
+        });
+
+        let client_handshake = perform_trusted_handshake(
+            &mut client_stream.tcp_stream,
+            &client_identity,
+            &client_trust_store,
+        );
+
+        assert!(client_handshake.is_ok());
+        assert!(server.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn handshake_payloads_are_exchanged() {
+        let (mut server_stream, mut client_stream) =
+            crate::tests::stablish_server_client_connection().split();
+
+        let server = std::thread::spawn(move || {
+            HandshakeBuilder::new()
+                .payload(b"music-server/1.0".to_vec())
+                .validate(|payload| payload.starts_with(b"music-client/"))
+                .perform(&mut server_stream.tcp_stream)
+        });
+
+        let client_handshake = HandshakeBuilder::new()
+            .payload(b"music-client/2.3".to_vec())
+            .perform(&mut client_stream.tcp_stream)
+            .unwrap();
+        let server_handshake = server.join().unwrap().unwrap();
+
+        assert_eq!(client_handshake.peer_payload(), Some(b"music-server/1.0".as_slice()));
+        assert_eq!(server_handshake.peer_payload(), Some(b"music-client/2.3".as_slice()));
+    }
+
+    #[test]
+    fn handshake_payload_is_rejected() {
+        let (mut server_stream, mut client_stream) =
+            crate::tests::stablish_server_client_connection().split();
+
+        let token = b"secret token".to_vec();
+
+        let server = std::thread::spawn(move || {
+            HandshakeBuilder::new()
+                .validate(move |payload| payload == token)
+                .perform(&mut server_stream.tcp_stream)
+        });
+
+        let client_handshake = HandshakeBuilder::new()
+            .payload(b"wrong token".to_vec())
+            .perform(&mut client_stream.tcp_stream);
+
+        assert!(matches!(server.join().unwrap(), Err(MtpError::PayloadRejected)));
+        assert!(client_handshake.is_err());
+    }
+
+    #[test]
+    fn capabilities_are_negotiated() {
+        let (server_stream, client_stream) =
+            crate::tests::stablish_server_client_connection().split();
+
+        let server = std::thread::spawn(move || {
+            HandshakeBuilder::new()
+                .capabilities(Capabilities {
+                    flags: capability::AES_256_GCM | capability::RESUMABLE,
+                    max_package_size: 4095,
+                    ..Capabilities::default()
+                })
+                .connect_stream(server_stream.tcp_stream)
+                .unwrap()
+        });
+
+        let client = HandshakeBuilder::new()
+            .capabilities(Capabilities {
+                flags: capability::AES_256_GCM,
+                ..Capabilities::default()
+            })
+            .connect_stream(client_stream.tcp_stream)
+            .unwrap();
+        let server = server.join().unwrap();
+
+        assert_eq!(client.capabilities, server.capabilities);
+        assert!(client.capabilities.supports(capability::AES_256_GCM));
+        assert!(!client.capabilities.supports(capability::RESUMABLE));
+        assert_eq!(client.capabilities.max_package_size, 4095);
+    }
 }
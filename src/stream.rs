@@ -1,33 +1,472 @@
-use std::net::{TcpStream, ToSocketAddrs};
-
-use crate::shake::{perform_handshake, Handshake};
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+};
 
+use crate::{
+    bufferable::Bufferable,
+    capabilities::{capability, Capabilities},
+    crypto::{SessionKey, SESSION_OVERHEAD},
+    error::{MtpError, Result},
+    identity::Identity,
+    multiplex::Multiplexer,
+    package::{
+        max_sealed_length,
+        meta::{compression, PackageType},
+        options::{ReadOptions, WriteOptions},
+        packages::{Packages, PackagesReader},
+        Package, PackageSize,
+    },
+    shake::{Handshake, HandshakeBuilder},
+    trust::TrustStore,
+    utils::MAX_EXTENDED_LENGTH,
+};
 
 #[derive(Debug)]
 pub struct Stream {
     pub tcp_stream: TcpStream,
     pub handshaken: Handshake,
+    /// Capabilities shared by both parties, [`Capabilities::BASELINE`] when `UNSHAKEN`
+    pub capabilities: Capabilities,
 }
 
 impl Stream {
-    pub fn connect_stream(mut tcp_stream: TcpStream) -> Self {
-        let handshaken = perform_handshake(&mut tcp_stream);
+    /// Starts configuring the handshake (identity, trust store, payload...)
+    /// performed when connecting
+    pub fn handshake() -> HandshakeBuilder {
+        HandshakeBuilder::new()
+    }
+
+    pub fn connect_stream(tcp_stream: TcpStream) -> Result<Self> {
+        Self::handshake().connect_stream(tcp_stream)
+    }
 
+    pub fn connect<T: ToSocketAddrs>(addr: T) -> Result<Self> {
+        Self::handshake().connect(addr)
+    }
+
+    /// Wraps a tcp stream without performing a handshake, nothing
+    /// sent or received on it will be encrypted
+    pub fn unshaken(tcp_stream: TcpStream) -> Self {
         Self {
             tcp_stream,
-            handshaken,
+            handshaken: Handshake::UNSHAKEN,
+            capabilities: Capabilities::BASELINE,
         }
     }
 
-    pub fn connect<T: ToSocketAddrs>(addr: T) -> Self {
-        let mut tcp_stream =
-            TcpStream::connect(addr).expect("Failed to connect to tcp socket address");
+    /// Same as [`Stream::connect_stream`] but the handshake is performed as the
+    /// given identity and the other party must be trusted by the trust store
+    pub fn connect_stream_trusted(
+        tcp_stream: TcpStream,
+        identity: &Identity,
+        trust_store: &TrustStore,
+    ) -> Result<Self> {
+        Self::handshake()
+            .identity(identity.clone())
+            .trust_store(trust_store.clone())
+            .connect_stream(tcp_stream)
+    }
 
-        let handshaken = perform_handshake(&mut tcp_stream);
+    /// Same as [`Stream::connect`] but the handshake is performed as the given
+    /// identity and fails with [`MtpError::UntrustedPeer`] when the other party
+    /// is not trusted by the trust store
+    pub fn connect_trusted<T: ToSocketAddrs>(
+        addr: T,
+        identity: &Identity,
+        trust_store: &TrustStore,
+    ) -> Result<Self> {
+        let tcp_stream = TcpStream::connect(addr).map_err(MtpError::Io)?;
 
-        Self {
-            tcp_stream,
-            handshaken
+        Self::connect_stream_trusted(tcp_stream, identity, trust_store)
+    }
+
+    /// Payload the other party attached to its shake, `None` when `UNSHAKEN`
+    pub fn peer_payload(&self) -> Option<&[u8]> {
+        self.handshaken.peer_payload()
+    }
+
+    /// Session key agreed during the handshake, fails with
+    /// [`MtpError::Unshaken`] when the stream is `UNSHAKEN`
+    pub(crate) fn session_key(&self) -> Result<&SessionKey> {
+        match &self.handshaken {
+            Handshake::SHAKEN(_, _, session_key) => Ok(session_key),
+            Handshake::UNSHAKEN => Err(MtpError::Unshaken),
+        }
+    }
+
+    /// Sends a package encrypted with the session key agreed during
+    /// the handshake, the package is marked as encrypted in the flags
+    /// of its `meta`.
+    pub fn send_package(&mut self, package: Package) -> Result<()> {
+        check_package_size(&self.capabilities, &package)?;
+
+        let encrypted_package = package.seal(self.session_key()?)?;
+
+        self.tcp_stream.write_all(&encrypted_package.to_buffer()?)?;
+
+        Ok(())
+    }
+
+    /// Receives a package sent with [`Stream::send_package`] and decrypts it,
+    /// packages not marked as encrypted or not of [`PackageType::PACKAGE`] are refused.
+    pub fn receive_package(&mut self) -> Result<Package> {
+        let session_key = self.session_key()?.clone();
+
+        let max_length = max_sealed_length(self.capabilities.max_package_size);
+
+        let package = Package::read_limited(&mut self.tcp_stream, max_length)?;
+        package.meta.check_type(PackageType::PACKAGE)?;
+
+        package.open(&session_key)
+    }
+
+    /// Sends packages with every package encrypted with the session key
+    pub fn send_packages(&mut self, mut packages: Packages) -> Result<()> {
+        let data = std::mem::take(&mut packages.data);
+
+        self.send_from_reader(
+            packages,
+            data.as_slice(),
+            Some(data.len()),
+            WriteOptions::new(),
+        )
+    }
+
+    /// Same as [`Stream::send_packages`] but the data is read from the reader one
+    /// package at a time, see [`Packages::write_from_reader`]. The session key of
+    /// the options is replaced by the one agreed during the handshake
+    pub fn send_from_reader<R: Read>(
+        &mut self,
+        packages: Packages,
+        reader: R,
+        length_hint: Option<usize>,
+        options: WriteOptions,
+    ) -> Result<()> {
+        let options = options
+            .session_key(self.session_key()?)
+            .max_package_size(self.capabilities.max_package_size);
+
+        check_packages(&self.capabilities, &packages)?;
+        if options.transfer_id.is_some() {
+            self.check_resumable()?;
         }
+
+        packages.write_from_reader(&mut self.tcp_stream, reader, length_hint, options)
+    }
+
+    fn check_resumable(&self) -> Result<()> {
+        if !self.capabilities.supports(capability::RESUMABLE) {
+            return Err(MtpError::Incompatible("resumable transfers were not negotiated"));
+        }
+
+        Ok(())
+    }
+
+    /// Receives packages sent with [`Stream::send_packages`],
+    /// every package must be marked as encrypted
+    pub fn receive_packages(&mut self) -> Result<Packages> {
+        let mut packages = Packages::new(vec![]);
+
+        self.receive_into(&mut packages.data, ReadOptions::new())?;
+
+        Ok(packages)
+    }
+
+    /// Same as [`Stream::receive_packages`] but the data is written to the sink
+    /// as it arrives, see [`Packages::read_into`]. The session key of the options
+    /// is replaced by the one agreed during the handshake
+    pub fn receive_into<W: Write>(&mut self, sink: W, options: ReadOptions) -> Result<usize> {
+        let options = options
+            .session_key(self.session_key()?)
+            .max_package_size(self.capabilities.max_package_size);
+
+        if options.log.is_some() {
+            self.check_resumable()?;
+        }
+
+        Packages::read_into(&mut self.tcp_stream, sink, options)
+    }
+
+    /// Same as [`Stream::receive_packages`] but the data is pulled through
+    /// [`Read`], see [`Packages::reader`]
+    pub fn receive_reader(&mut self) -> Result<PackagesReader<'_, TcpStream>> {
+        let session_key = self.session_key()?.clone();
+
+        PackagesReader::new(
+            &mut self.tcp_stream,
+            Some(session_key),
+            self.capabilities.max_package_size,
+        )
+    }
+
+    /// Turns the stream into channels carrying concurrent transfers,
+    /// see [`Multiplexer`]
+    pub fn multiplex(self) -> Result<Multiplexer> {
+        Multiplexer::new(self)
+    }
+}
+
+/// Ensures a package fits the negotiated size and still fits
+/// an extended length header once sealed
+pub(crate) fn check_package_size(capabilities: &Capabilities, package: &Package) -> Result<()> {
+    let max_package_size = capabilities
+        .max_package_size
+        .min(MAX_EXTENDED_LENGTH - SESSION_OVERHEAD);
+
+    if package.data.len() > max_package_size {
+        return Err(MtpError::OversizedLength {
+            length: package.data.len(),
+            max: max_package_size,
+        });
+    }
+
+    Ok(())
+}
+
+/// Ensures the packages are not split above the negotiated size and are only
+/// compressed with a negotiated codec, adaptive sizes never grow above it
+pub(crate) fn check_packages(capabilities: &Capabilities, packages: &Packages) -> Result<()> {
+    let adaptive = *packages.package_size() == PackageSize::ADAPTIVE;
+
+    if !adaptive && packages.package_size().get_value() > capabilities.max_package_size {
+        return Err(MtpError::OversizedLength {
+            length: packages.package_size().get_value(),
+            max: capabilities.max_package_size,
+        });
+    }
+
+    let codec = match packages.compression() {
+        compression::NONE => return Ok(()),
+        compression::ZSTD => capability::ZSTD,
+        compression::LZ4 => capability::LZ4,
+        _ => return Err(MtpError::MalformedHeader("unknown package compression")),
+    };
+
+    if !capabilities.supports(codec) {
+        return Err(MtpError::Incompatible("compression codec was not negotiated"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::{
+        bufferable::Bufferable,
+        capabilities::Capabilities,
+        error::MtpError,
+        package::{
+            meta::{PackageMeta, PackageType},
+            options::WriteOptions,
+            packages::{Packages, PackagesBatchSize},
+            Package, PackageSize,
+        },
+        shake::perform_handshake,
+        stream::check_package_size,
+        utils::MAX_EXTENDED_LENGTH,
+    };
+
+    #[test]
+    fn send_receive_encrypted_package() {
+        let (mut server_stream, mut client_stream) =
+            crate::tests::stablish_server_client_connection().split();
+
+        let server = std::thread::spawn(move || {
+            server_stream.handshaken = perform_handshake(&mut server_stream.tcp_stream).unwrap();
+            server_stream
+        });
+
+        client_stream.handshaken = perform_handshake(&mut client_stream.tcp_stream).unwrap();
+        let mut server_stream = server.join().unwrap();
+
+        let data = (0..1000).map(|i| (i % 256) as u8).collect::<Vec<u8>>();
+        let meta = PackageMeta::new(1, PackageType::PACKAGE);
+        let package = Package::new(data, meta);
+
+        client_stream.send_package(package.clone()).unwrap();
+        let received = server_stream.receive_package().unwrap();
+
+        assert_eq!(package, received);
+        assert!(!received.meta.is_encrypted());
+    }
+
+    #[test]
+    fn refuse_unencrypted_package() {
+        let (mut server_stream, mut client_stream) =
+            crate::tests::stablish_server_client_connection().split();
+
+        let server = std::thread::spawn(move || {
+            server_stream.handshaken = perform_handshake(&mut server_stream.tcp_stream).unwrap();
+            server_stream
+        });
+
+        client_stream.handshaken = perform_handshake(&mut client_stream.tcp_stream).unwrap();
+        let mut server_stream = server.join().unwrap();
+
+        let meta = PackageMeta::new(1, PackageType::PACKAGE);
+        let package = Package::new(b"plain text".to_vec(), meta);
+
+        client_stream
+            .tcp_stream
+            .write_all(&package.to_buffer().unwrap())
+            .unwrap();
+
+        assert!(matches!(
+            server_stream.receive_package(),
+            Err(MtpError::NotEncrypted)
+        ));
+    }
+
+    #[test]
+    fn refuse_package_of_another_type() {
+        let (mut server_stream, mut client_stream) =
+            crate::tests::handshaken_pair(Capabilities::default());
+
+        // Part of a transfer rather than a package on its own
+        let meta = PackageMeta::new(1, PackageType::PACKAGES);
+        client_stream
+            .send_package(Package::new(b"stray".to_vec(), meta))
+            .unwrap();
+
+        assert!(matches!(
+            server_stream.receive_package(),
+            Err(MtpError::MalformedHeader(_))
+        ));
+    }
+
+    #[test]
+    fn send_receive_extended_package() {
+        let (mut server_stream, mut client_stream) = crate::tests::handshaken_pair(Capabilities {
+            max_package_size: MAX_EXTENDED_LENGTH,
+            ..Capabilities::default()
+        });
+
+        let server = std::thread::spawn(move || server_stream.receive_package().unwrap());
+
+        let meta = PackageMeta::new(1, PackageType::PACKAGE);
+        let package = Package::new(vec![5; usize::pow(2, 24) + 1], meta);
+
+        // Above the 3 bytes length header unless both parties raise their limit
+        assert!(matches!(
+            check_package_size(&Capabilities::default(), &package),
+            Err(MtpError::OversizedLength { .. })
+        ));
+
+        client_stream.send_package(package.clone()).unwrap();
+
+        assert_eq!(server.join().unwrap(), package);
+    }
+
+    #[test]
+    fn refuse_package_above_negotiated_size() {
+        let (mut server_stream, mut client_stream) = crate::tests::handshaken_pair(Capabilities {
+            max_package_size: 4095,
+            ..Capabilities::default()
+        });
+
+        // Written around the sending checks, as a peer ignoring the negotiated size would
+        let meta = PackageMeta::new(1, PackageType::PACKAGE);
+        let package = Package::new(vec![5; 10_000], meta)
+            .seal(client_stream.session_key().unwrap())
+            .unwrap();
+
+        client_stream
+            .tcp_stream
+            .write_all(&package.to_buffer().unwrap())
+            .unwrap();
+
+        assert!(matches!(
+            server_stream.receive_package(),
+            Err(MtpError::OversizedLength { length, max: _ }) if length > 10_000
+        ));
+    }
+
+    #[test]
+    fn refuse_packages_above_negotiated_size() {
+        let (mut server_stream, mut client_stream) = crate::tests::handshaken_pair(Capabilities {
+            max_package_size: 4095,
+            ..Capabilities::default()
+        });
+
+        let client = std::thread::spawn(move || {
+            let options = WriteOptions::new().session_key(client_stream.session_key().unwrap());
+            let mut packages = Packages::new(vec![]);
+            packages.set_package_size(PackageSize::LARGE);
+
+            // Fails once the other party closes the connection
+            let data = vec![5; 100_000];
+            let _ = packages.write_from_reader(
+                &mut client_stream.tcp_stream,
+                data.as_slice(),
+                Some(data.len()),
+                options,
+            );
+        });
+
+        assert!(matches!(
+            server_stream.receive_packages(),
+            Err(MtpError::OversizedLength { .. })
+        ));
+
+        drop(server_stream);
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn adaptive_packages_fit_negotiated_size() {
+        let (mut server_stream, mut client_stream) = crate::tests::handshaken_pair(Capabilities {
+            max_package_size: 4095,
+            ..Capabilities::default()
+        });
+
+        let server = std::thread::spawn(move || server_stream.receive_packages().unwrap());
+
+        // Adaptive sizes start above the negotiated size
+        let data = (0..100_000).map(|i| (i % 256) as u8).collect::<Vec<u8>>();
+        let mut packages = Packages::new(data.clone());
+        packages.set_package_size(PackageSize::ADAPTIVE);
+        packages.set_batch_size(PackagesBatchSize::SMALL);
+
+        client_stream.send_packages(packages).unwrap();
+
+        assert_eq!(server.join().unwrap().data, data);
+    }
+
+    #[test]
+    fn send_receive_encrypted_packages() {
+        let (mut server_stream, mut client_stream) =
+            crate::tests::stablish_server_client_connection().split();
+
+        let server = std::thread::spawn(move || {
+            server_stream.handshaken = perform_handshake(&mut server_stream.tcp_stream).unwrap();
+            server_stream.receive_packages().unwrap()
+        });
+
+        client_stream.handshaken = perform_handshake(&mut client_stream.tcp_stream).unwrap();
+
+        let data = (0..100_000).map(|i| (i % 256) as u8).collect::<Vec<u8>>();
+        let mut packages = Packages::new(data.clone());
+        packages.set_package_size(PackageSize::MEDIUM);
+        packages.set_batch_size(PackagesBatchSize::SMALL);
+
+        client_stream.send_packages(packages).unwrap();
+
+        assert_eq!(server.join().unwrap().data, data);
+    }
+
+    #[test]
+    fn unshaken_stream_refuses_encryption() {
+        let (_, mut client_stream) = crate::tests::stablish_server_client_connection().split();
+
+        let meta = PackageMeta::new(1, PackageType::PACKAGE);
+        let package = Package::new(b"secret".to_vec(), meta);
+
+        assert!(matches!(
+            client_stream.send_package(package),
+            Err(MtpError::Unshaken)
+        ));
     }
 }
@@ -0,0 +1,226 @@
+use std::{collections::HashSet, fmt, fs, path::Path, str::FromStr, sync::Arc};
+
+use openssl::{
+    pkey::{PKey, Public},
+    sha::sha256,
+};
+
+use crate::error::{MtpError, Result};
+
+/// SHA-256 of the DER encoded public key of a party
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fingerprint(pub [u8; 32]);
+
+impl Fingerprint {
+    pub fn of(public_key: &PKey<Public>) -> Result<Self> {
+        Ok(Self(sha256(&public_key.public_key_to_der()?)))
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Fingerprint {
+    type Err = MtpError;
+
+    /// Parses the 64 hex characters written by [`Fingerprint`]'s `Display`
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || MtpError::MalformedHeader("fingerprint must be 64 hex characters");
+
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(invalid());
+        }
+
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+
+        Ok(Self(bytes))
+    }
+}
+
+/// Decides whether a fingerprint is trusted, closures can capture
+/// state such as a set of fingerprints loaded at runtime
+pub type FingerprintValidator = Arc<dyn Fn(&Fingerprint) -> bool + Send + Sync>;
+
+/// Decides which public keys are accepted from the other party during the handshake
+#[derive(Clone, Default)]
+pub enum TrustStore {
+    /// Any public key is accepted, the other party still has to prove
+    /// it owns the private key but its identity is not checked
+    #[default]
+    ANY,
+
+    /// Only public keys whose fingerprint has been pinned are accepted
+    PINNED(HashSet<Fingerprint>),
+
+    /// The callback decides whether the fingerprint is accepted
+    CALLBACK(FingerprintValidator),
+}
+
+impl fmt::Debug for TrustStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ANY => write!(f, "ANY"),
+            Self::PINNED(fingerprints) => f.debug_tuple("PINNED").field(fingerprints).finish(),
+            Self::CALLBACK(_) => write!(f, "CALLBACK"),
+        }
+    }
+}
+
+impl TrustStore {
+    pub fn callback<F: Fn(&Fingerprint) -> bool + Send + Sync + 'static>(callback: F) -> Self {
+        Self::CALLBACK(Arc::new(callback))
+    }
+
+    pub fn pinned<I: IntoIterator<Item = Fingerprint>>(fingerprints: I) -> Self {
+        Self::PINNED(fingerprints.into_iter().collect())
+    }
+
+    /// Pins one more fingerprint, turns any other kind of store into a pinned one
+    pub fn pin(&mut self, fingerprint: Fingerprint) {
+        match self {
+            Self::PINNED(fingerprints) => {
+                fingerprints.insert(fingerprint);
+            }
+            _ => *self = Self::pinned([fingerprint]),
+        }
+    }
+
+    /// Loads a known hosts file, one fingerprint per line optionally
+    /// followed by a name, empty lines and lines starting with `#` are skipped
+    /// ```text
+    /// # fingerprint                                                      name
+    /// 3f0c...e1a2 music-server
+    /// ```
+    pub fn load_known_hosts<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(MtpError::Io)?;
+        let mut fingerprints = HashSet::new();
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(fingerprint) = line.split_whitespace().next() {
+                fingerprints.insert(fingerprint.parse()?);
+            }
+        }
+
+        Ok(Self::PINNED(fingerprints))
+    }
+
+    /// Writes the pinned fingerprints in the format read by [`TrustStore::load_known_hosts`],
+    /// fails with [`MtpError::NotPinned`] for any other kind of store as it would load
+    /// back as a store trusting nobody
+    pub fn save_known_hosts<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let Self::PINNED(fingerprints) = self else {
+            return Err(MtpError::NotPinned);
+        };
+
+        let mut content = String::new();
+        for fingerprint in fingerprints {
+            content.push_str(&format!("{}\n", fingerprint));
+        }
+
+        fs::write(path, content).map_err(MtpError::Io)
+    }
+
+    pub fn is_trusted(&self, fingerprint: &Fingerprint) -> bool {
+        match self {
+            Self::ANY => true,
+            Self::PINNED(fingerprints) => fingerprints.contains(fingerprint),
+            Self::CALLBACK(callback) => callback(fingerprint),
+        }
+    }
+
+    /// Fails with [`MtpError::UntrustedPeer`] when the public key is not trusted
+    pub fn verify(&self, public_key: &PKey<Public>) -> Result<Fingerprint> {
+        let fingerprint = Fingerprint::of(public_key)?;
+
+        if !self.is_trusted(&fingerprint) {
+            return Err(MtpError::UntrustedPeer(fingerprint));
+        }
+
+        Ok(fingerprint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use openssl::{pkey::PKey, rsa::Rsa};
+
+    use crate::{
+        error::MtpError,
+        trust::{Fingerprint, TrustStore},
+    };
+
+    #[test]
+    fn fingerprint_display_parse() {
+        let key = Rsa::generate(2048).unwrap();
+        let public_key = PKey::public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap();
+
+        let fingerprint = Fingerprint::of(&public_key).unwrap();
+        let parsed = fingerprint.to_string().parse::<Fingerprint>().unwrap();
+
+        assert_eq!(fingerprint, parsed);
+        assert!("not a fingerprint".parse::<Fingerprint>().is_err());
+    }
+
+    #[test]
+    fn known_hosts_file() {
+        let path = std::env::temp_dir().join(format!("mtp_known_hosts_{}", std::process::id()));
+
+        let trusted = Fingerprint([7; 32]);
+        let untrusted = Fingerprint([9; 32]);
+
+        std::fs::write(&path, format!("# trusted peers\n\n{} server\n", trusted)).unwrap();
+        let store = TrustStore::load_known_hosts(&path).unwrap();
+
+        store.save_known_hosts(&path).unwrap();
+        let store = TrustStore::load_known_hosts(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(store.is_trusted(&trusted));
+        assert!(!store.is_trusted(&untrusted));
+
+        assert!(matches!(
+            TrustStore::ANY.save_known_hosts(&path),
+            Err(MtpError::NotPinned)
+        ));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn pinned_store_rejects_unknown_key() {
+        let key = Rsa::generate(2048).unwrap();
+        let public_key = PKey::public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap();
+
+        let store = TrustStore::pinned([Fingerprint([0; 32])]);
+
+        assert!(matches!(
+            store.verify(&public_key),
+            Err(MtpError::UntrustedPeer(_))
+        ));
+        assert!(TrustStore::ANY.verify(&public_key).is_ok());
+    }
+
+    #[test]
+    fn callback_captures_state() {
+        let revoked = HashSet::from([Fingerprint([1; 32])]);
+        let store = TrustStore::callback(move |fingerprint| !revoked.contains(fingerprint));
+
+        assert!(store.is_trusted(&Fingerprint([2; 32])));
+        assert!(!store.clone().is_trusted(&Fingerprint([1; 32])));
+    }
+}